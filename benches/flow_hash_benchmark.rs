@@ -0,0 +1,96 @@
+/// Benchmarks for VsapiFiveTuple::flow_hash vs the derived Hash.
+#[cfg(feature = "vsapi")]
+use criterion::{criterion_group, criterion_main};
+
+#[cfg(feature = "vsapi")]
+mod bench_impl {
+    use criterion::Criterion;
+    use std::collections::HashMap;
+    use std::hash::{BuildHasher, RandomState};
+    use std::hint::black_box;
+    use std::net::IpAddr;
+    use zpr::packet_info::L3Type;
+    use zpr::vsapi_types::{FlowKey, FlowMap, VsapiFiveTuple, vsapi_ip_number};
+
+    fn make_fts(n: usize) -> Vec<VsapiFiveTuple> {
+        (0..n)
+            .map(|i| {
+                let mut source = [0u8; 16];
+                source[0] = 0xfd;
+                source[8..16].copy_from_slice(&(i as u64).to_be_bytes());
+                VsapiFiveTuple::new(
+                    L3Type::Ipv6,
+                    IpAddr::from(source),
+                    IpAddr::from([0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+                    vsapi_ip_number::TCP,
+                    40000 + (i % 20000) as u16,
+                    443,
+                )
+            })
+            .collect()
+    }
+
+    pub fn bench_hash_one(c: &mut Criterion) {
+        let fts = make_fts(1024);
+        let state = RandomState::new();
+        let mut group = c.benchmark_group("five_tuple_hash");
+        group.bench_function("derived_siphash", |b| {
+            b.iter(|| {
+                for ft in &fts {
+                    black_box(state.hash_one(black_box(ft)));
+                }
+            })
+        });
+        group.bench_function("flow_hash", |b| {
+            b.iter(|| {
+                for ft in &fts {
+                    black_box(black_box(ft).flow_hash());
+                }
+            })
+        });
+        group.finish();
+    }
+
+    pub fn bench_map_lookup(c: &mut Criterion) {
+        let fts = make_fts(100_000);
+        let std_map: HashMap<VsapiFiveTuple, usize> =
+            fts.iter().enumerate().map(|(i, ft)| (*ft, i)).collect();
+        let flow_map: FlowMap<usize> = fts
+            .iter()
+            .enumerate()
+            .map(|(i, ft)| (FlowKey(*ft), i))
+            .collect();
+
+        let mut group = c.benchmark_group("five_tuple_map_lookup");
+        group.bench_function("hashmap_derived", |b| {
+            b.iter(|| {
+                for ft in fts.iter().step_by(97) {
+                    black_box(std_map.get(ft));
+                }
+            })
+        });
+        group.bench_function("flow_map", |b| {
+            b.iter(|| {
+                for ft in fts.iter().step_by(97) {
+                    black_box(flow_map.get(&FlowKey(*ft)));
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+#[cfg(feature = "vsapi")]
+criterion_group!(
+    benches,
+    bench_impl::bench_hash_one,
+    bench_impl::bench_map_lookup,
+);
+
+#[cfg(feature = "vsapi")]
+criterion_main!(benches);
+
+#[cfg(not(feature = "vsapi"))]
+fn main() {
+    eprintln!("Benchmarks require 'vsapi'.\n");
+}
@@ -1,13 +1,19 @@
 fn main() {
-    capnpc::CompilerCommand::new()
-        .src_prefix("zpr-policy")
-        .file("zpr-policy/policy.capnp")
-        .run()
-        .expect("failed to compile zpr-policy capnp schema");
+    // The schemas are only needed for the Cap'n Proto backed modules, so a
+    // no_std build does not need the submodules checked out.
+    if std::env::var_os("CARGO_FEATURE_POLICY").is_some() {
+        capnpc::CompilerCommand::new()
+            .src_prefix("zpr-policy")
+            .file("zpr-policy/policy.capnp")
+            .run()
+            .expect("failed to compile zpr-policy capnp schema");
+    }
 
-    capnpc::CompilerCommand::new()
-        .src_prefix("zpr-vsapi")
-        .file("zpr-vsapi/vs.capnp")
-        .run()
-        .expect("failed to compile zpr-vsapi capnp schema");
+    if std::env::var_os("CARGO_FEATURE_VSAPI").is_some() {
+        capnpc::CompilerCommand::new()
+            .src_prefix("zpr-vsapi")
+            .file("zpr-vsapi/vs.capnp")
+            .run()
+            .expect("failed to compile zpr-vsapi capnp schema");
+    }
 }
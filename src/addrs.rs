@@ -1,4 +1,8 @@
-use std::net::{IpAddr, Ipv6Addr};
+use core::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::packet_info::{L3Type, SubstrateAddr};
+#[cfg(feature = "vsapi")]
+use crate::vsapi_types::VsapiFiveTuple;
 
 /// Default prefix length for local tun IPv6 ZPR addresses.
 pub const ZPRNET_PREFIX_LEN: u8 = 32;
@@ -15,3 +19,136 @@ pub const VISA_SERVICE_ADDR: IpAddr =
     IpAddr::V6(Ipv6Addr::from_bits(ZPR_INTERNAL_NETWORK.to_bits() | 1));
 pub const VISA_SERVICE_PROTO: u8 = 6 /* TCP */;
 pub const VISA_SERVICE_PORT: u16 = 5002;
+pub const VISA_SERVICE_L3_TYPE: L3Type = L3Type::Ipv6;
+
+/// Destination of every flow to the visa service.
+pub const VISA_SERVICE_DST: SocketAddr = SocketAddr::new(VISA_SERVICE_ADDR, VISA_SERVICE_PORT);
+
+/// Substrate address for a tether on `ip` at the default tether port.
+pub fn default_tether_addr(ip: IpAddr) -> SubstrateAddr {
+    SubstrateAddr::new(ip, DEFAULT_TETHER_PORT)
+}
+
+/// Substrate address for a link on `ip` at the default link port.
+pub fn default_link_addr(ip: IpAddr) -> SubstrateAddr {
+    SubstrateAddr::new(ip, DEFAULT_LINK_PORT)
+}
+
+/// Tether socket address on [ZPR_TEMP_LOCAL_ADDRESS], for use during bootstrap
+/// before a ZPR address has been allocated.
+pub const fn temp_local_tether_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(ZPR_TEMP_LOCAL_ADDRESS), DEFAULT_TETHER_PORT)
+}
+
+/// Link socket address on [ZPR_TEMP_LOCAL_ADDRESS], for use during bootstrap
+/// before a ZPR address has been allocated.
+pub const fn temp_local_link_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(ZPR_TEMP_LOCAL_ADDRESS), DEFAULT_LINK_PORT)
+}
+
+/// Socket address of the visa service.
+pub fn visa_service_sockaddr() -> SocketAddr {
+    VISA_SERVICE_DST
+}
+
+/// Five tuple for a request from `from`:`from_port` to the visa service.
+/// This is a const fn so a caller with a fixed source can make the tuple a const.
+/// The visa service is IPv6 only, so None if `from` is IPv4.
+#[cfg(feature = "vsapi")]
+pub const fn visa_service_five_tuple(from: IpAddr, from_port: u16) -> Option<VsapiFiveTuple> {
+    match from {
+        IpAddr::V6(_) => Some(VsapiFiveTuple::new(
+            VISA_SERVICE_L3_TYPE,
+            from,
+            VISA_SERVICE_ADDR,
+            VISA_SERVICE_PROTO,
+            from_port,
+            VISA_SERVICE_PORT,
+        )),
+        IpAddr::V4(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_substrate_addrs() {
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let tether = default_tether_addr(ip);
+        assert_eq!(tether.ip(), ip);
+        assert_eq!(tether.port(), 5000);
+        let link = default_link_addr(ip);
+        assert_eq!(link.ip(), ip);
+        assert_eq!(link.port(), 5001);
+    }
+
+    #[test]
+    fn test_temp_local_addrs() {
+        const TETHER: SocketAddr = temp_local_tether_addr();
+        let expected: IpAddr = "fc00:5a:50:52::1".parse().unwrap();
+        assert_eq!(TETHER.ip(), expected);
+        assert_eq!(TETHER.port(), 5000);
+        let link = temp_local_link_addr();
+        assert_eq!(link.ip(), expected);
+        assert_eq!(link.port(), 5001);
+        assert_eq!(link.to_string(), "[fc00:5a:50:52::1]:5001");
+    }
+
+    #[test]
+    fn test_visa_service_sockaddr() {
+        let sa = visa_service_sockaddr();
+        assert_eq!(sa.ip(), VISA_SERVICE_ADDR);
+        assert_eq!(sa.port(), 5002);
+    }
+
+    #[cfg(feature = "vsapi")]
+    #[test]
+    fn test_visa_service_five_tuple() {
+        use crate::vsapi_types::vsapi_ip_number;
+
+        let from: IpAddr = "fd5a:5052::99".parse().unwrap();
+        let ft = visa_service_five_tuple(from, 40000).unwrap();
+        assert_eq!(ft.src_addr(), from);
+        assert_eq!(ft.src_port(), 40000);
+        assert_eq!(ft.dst_addr(), VISA_SERVICE_ADDR);
+        assert_eq!(ft.dst_port(), VISA_SERVICE_PORT);
+        assert_eq!(ft.protocol(), vsapi_ip_number::TCP);
+        assert_eq!(ft.l3_type(), L3Type::Ipv6);
+
+        let from: IpAddr = "10.0.0.99".parse().unwrap();
+        assert_eq!(visa_service_five_tuple(from, 40000), None);
+    }
+
+    #[test]
+    fn test_visa_service_consts() {
+        assert_eq!(VISA_SERVICE_DST, visa_service_sockaddr());
+        assert_eq!(
+            VISA_SERVICE_L3_TYPE,
+            L3Type::new_from_addr(&VISA_SERVICE_ADDR)
+        );
+    }
+
+    #[cfg(feature = "vsapi")]
+    #[test]
+    fn test_const_visa_service_five_tuple_in_match() {
+        const LOCAL: IpAddr = IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, 0x99));
+        const VS_FLOW: VsapiFiveTuple = visa_service_five_tuple(LOCAL, 40000).unwrap();
+
+        let classify = |ft: VsapiFiveTuple| match ft {
+            f if f == VS_FLOW => "vs",
+            f if f.dst_addr() == VISA_SERVICE_DST.ip() => "vs-other",
+            _ => "other",
+        };
+        assert_eq!(
+            classify(visa_service_five_tuple(LOCAL, 40000).unwrap()),
+            "vs"
+        );
+        assert_eq!(
+            classify(visa_service_five_tuple(LOCAL, 40001).unwrap()),
+            "vs-other"
+        );
+        assert_eq!(classify(VS_FLOW.reversed()), "other");
+    }
+}
@@ -1,3 +1,68 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use thiserror::Error;
+
+/// Errors from the runtime DN encoder and decoder.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DnError {
+    #[error("DN component too long to encode")]
+    TooLong,
+
+    #[error("malformed DER: {0}")]
+    Malformed(&'static str),
+
+    #[error("unexpected attribute OID in DN")]
+    UnexpectedOid,
+
+    #[error("DN value is not valid UTF-8")]
+    NotUtf8,
+}
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_UTF8STRING: u8 = 0x0C;
+const TAG_PRINTABLESTRING: u8 = 0x13;
+const TAG_IA5STRING: u8 = 0x16;
+
+/// DER encoding of the commonName attribute OID (2.5.4.3).
+const OID_CN: &[u8] = &[2 * 40 + 5, 4, 3];
+/// DER encoding of the organizationName attribute OID (2.5.4.10).
+const OID_O: &[u8] = &[2 * 40 + 5, 4, 10];
+/// DER encoding of the organizationalUnitName attribute OID (2.5.4.11).
+const OID_OU: &[u8] = &[2 * 40 + 5, 4, 11];
+
+/// The DN attribute types we can encode and decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DnAttr {
+    /// commonName (2.5.4.3)
+    CN,
+    /// organizationName (2.5.4.10)
+    O,
+    /// organizationalUnitName (2.5.4.11)
+    OU,
+}
+
+impl DnAttr {
+    /// DER encoded OID (without tag and length).
+    fn oid(&self) -> &'static [u8] {
+        match self {
+            DnAttr::CN => OID_CN,
+            DnAttr::O => OID_O,
+            DnAttr::OU => OID_OU,
+        }
+    }
+
+    fn from_oid(oid: &[u8]) -> Result<Self, DnError> {
+        match oid {
+            OID_CN => Ok(DnAttr::CN),
+            OID_O => Ok(DnAttr::O),
+            OID_OU => Ok(DnAttr::OU),
+            _ => Err(DnError::UnexpectedOid),
+        }
+    }
+}
+
 // Well-known DNs.
 
 const DN_CN_DER_PREFIX_LEN: usize = 13;
@@ -40,3 +105,252 @@ macro_rules! dn_cn_der {
 
 pub const VISA_SERVICE_CN: &str = "vs.zpr";
 pub const VISA_SERVICE_DN: &[u8] = &dn_cn_der!(VISA_SERVICE_CN);
+
+/// DER encoding of a DN (an RDNSequence) with one attribute per RDN. The RDNs are
+/// encoded in slice order, which is most significant first (the reverse of the
+/// usual string form, so `CN=vs.zpr, O=ZPR` is `[(O, "ZPR"), (CN, "vs.zpr")]`).
+pub fn encode_dn(rdns: &[(DnAttr, &str)]) -> Result<Vec<u8>, DnError> {
+    let mut seq = Vec::new();
+    for (attr, value) in rdns {
+        let atv = encode_tlv(
+            TAG_SEQUENCE,
+            &[
+                encode_tlv(TAG_OID, attr.oid())?,
+                encode_tlv(TAG_UTF8STRING, value.as_bytes())?,
+            ]
+            .concat(),
+        )?;
+        seq.extend(encode_tlv(TAG_SET, &atv)?);
+    }
+    encode_tlv(TAG_SEQUENCE, &seq)
+}
+
+/// Runtime version of the const encoder: the DER encoding of a DN with a single
+/// commonName RDN.
+pub fn encode_dn_cn(cn: &str) -> Result<Vec<u8>, DnError> {
+    encode_dn(&[(DnAttr::CN, cn)])
+}
+
+/// Decode a DN into its (attribute, value) pairs, in encoded order. Each RDN must
+/// hold a single attribute of a type in [DnAttr] with a UTF8String, PrintableString
+/// or IA5String value.
+pub fn decode_dn(der: &[u8]) -> Result<Vec<(DnAttr, String)>, DnError> {
+    let (mut rdns, rest) = read_tlv(der, TAG_SEQUENCE)?;
+    if !rest.is_empty() {
+        return Err(DnError::Malformed("trailing data after DN"));
+    }
+    let mut out = Vec::new();
+    while !rdns.is_empty() {
+        let (atvs, rest) = read_tlv(rdns, TAG_SET)?;
+        rdns = rest;
+        let (atv, rest) = read_tlv(atvs, TAG_SEQUENCE)?;
+        if !rest.is_empty() {
+            return Err(DnError::Malformed("multi-valued RDN"));
+        }
+        let (oid, value) = read_tlv(atv, TAG_OID)?;
+        let attr = DnAttr::from_oid(oid)?;
+        let (tag, value, rest) = read_any_tlv(value)?;
+        if !matches!(tag, TAG_UTF8STRING | TAG_PRINTABLESTRING | TAG_IA5STRING) {
+            return Err(DnError::Malformed("unsupported string type"));
+        }
+        if !rest.is_empty() {
+            return Err(DnError::Malformed("trailing data after attribute value"));
+        }
+        let value = String::from_utf8(value.to_vec()).map_err(|_| DnError::NotUtf8)?;
+        out.push((attr, value));
+    }
+    Ok(out)
+}
+
+/// Decode a DN with a single commonName RDN (as produced by [encode_dn_cn]) and
+/// return the common name.
+pub fn decode_dn_cn(der: &[u8]) -> Result<String, DnError> {
+    let mut rdns = decode_dn(der)?;
+    match rdns.pop() {
+        Some((DnAttr::CN, cn)) if rdns.is_empty() => Ok(cn),
+        Some((DnAttr::CN, _)) => Err(DnError::Malformed("more than one RDN")),
+        Some(_) => Err(DnError::UnexpectedOid),
+        None => Err(DnError::Malformed("empty DN")),
+    }
+}
+
+/// Compare two DER encoded DNs by meaning rather than bytes. Both are decoded
+/// and compared as unordered collections of (attribute, value) pairs, with the
+/// values compared case insensitively and with whitespace runs collapsed (as for
+/// the X.520 caseIgnoreMatch rule). So RDN order and string type do not matter.
+pub fn dn_equal(a: &[u8], b: &[u8]) -> Result<bool, DnError> {
+    Ok(normalized_dn(a)? == normalized_dn(b)?)
+}
+
+fn normalized_dn(der: &[u8]) -> Result<Vec<(DnAttr, String)>, DnError> {
+    let mut rdns: Vec<(DnAttr, String)> = decode_dn(der)?
+        .into_iter()
+        .map(|(attr, value)| {
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            (attr, value.to_lowercase())
+        })
+        .collect();
+    rdns.sort();
+    Ok(rdns)
+}
+
+/// Encode a tag, DER length and contents. Lengths up to 0xFFFF are supported.
+fn encode_tlv(tag: u8, contents: &[u8]) -> Result<Vec<u8>, DnError> {
+    let len = contents.len();
+    let mut out = Vec::with_capacity(len + 4);
+    out.push(tag);
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len <= 0xFF {
+        out.extend_from_slice(&[0x81, len as u8]);
+    } else if len <= 0xFFFF {
+        out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    } else {
+        return Err(DnError::TooLong);
+    }
+    out.extend_from_slice(contents);
+    Ok(out)
+}
+
+/// Read one TLV with the expected tag from the front of `input`. Returns the
+/// contents and whatever follows.
+fn read_tlv(input: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8]), DnError> {
+    let (tag, contents, rest) = read_any_tlv(input)?;
+    if tag != expected_tag {
+        return Err(DnError::Malformed("unexpected tag"));
+    }
+    Ok((contents, rest))
+}
+
+/// Read one TLV from the front of `input`. Returns the tag, the contents and
+/// whatever follows.
+fn read_any_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8]), DnError> {
+    let (&tag, rest) = input
+        .split_first()
+        .ok_or(DnError::Malformed("unexpected end of input"))?;
+    let (&first, rest) = rest
+        .split_first()
+        .ok_or(DnError::Malformed("missing length"))?;
+    let (len, rest) = match first {
+        0..=0x7F => (first as usize, rest),
+        0x81 => match rest.split_first() {
+            Some((&l, rest)) if l >= 0x80 => (l as usize, rest),
+            Some(_) => return Err(DnError::Malformed("non-minimal length")),
+            None => return Err(DnError::Malformed("missing length")),
+        },
+        0x82 => match rest {
+            [hi, lo, rest @ ..] if *hi != 0 => ((*hi as usize) << 8 | *lo as usize, rest),
+            [_, _, ..] => return Err(DnError::Malformed("non-minimal length")),
+            _ => return Err(DnError::Malformed("missing length")),
+        },
+        _ => return Err(DnError::Malformed("unsupported length form")),
+    };
+    if rest.len() < len {
+        return Err(DnError::Malformed("length exceeds input"));
+    }
+    let (contents, rest) = rest.split_at(len);
+    Ok((tag, contents, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_dn_cn_matches_const() {
+        assert_eq!(encode_dn_cn(VISA_SERVICE_CN).unwrap(), VISA_SERVICE_DN);
+    }
+
+    #[test]
+    fn test_decode_dn_cn() {
+        assert_eq!(decode_dn_cn(VISA_SERVICE_DN).unwrap(), VISA_SERVICE_CN);
+        let long_cn = "a".repeat(300);
+        let der = encode_dn_cn(&long_cn).unwrap();
+        assert_eq!(decode_dn_cn(&der).unwrap(), long_cn);
+    }
+
+    #[test]
+    fn test_encode_dn_cn_too_long() {
+        let cn = "a".repeat(0x10000);
+        assert_eq!(encode_dn_cn(&cn), Err(DnError::TooLong));
+    }
+
+    #[test]
+    fn test_decode_dn_cn_errors() {
+        // Truncated.
+        assert!(matches!(
+            decode_dn_cn(&VISA_SERVICE_DN[..VISA_SERVICE_DN.len() - 1]),
+            Err(DnError::Malformed(_))
+        ));
+        assert!(matches!(decode_dn_cn(&[]), Err(DnError::Malformed(_))));
+        // Not a SEQUENCE.
+        assert!(matches!(
+            decode_dn_cn(&[0x31, 0x00]),
+            Err(DnError::Malformed(_))
+        ));
+        // OID 2.5.4.10 (organizationName) instead of commonName.
+        let mut der = VISA_SERVICE_DN.to_vec();
+        der[10] = 10;
+        assert_eq!(decode_dn_cn(&der), Err(DnError::UnexpectedOid));
+        // Invalid UTF-8 in the value.
+        let mut der = VISA_SERVICE_DN.to_vec();
+        der[13] = 0xFF;
+        assert_eq!(decode_dn_cn(&der), Err(DnError::NotUtf8));
+    }
+
+    #[test]
+    fn test_encode_dn_with_organization() {
+        let rdns = [(DnAttr::O, "ZPR"), (DnAttr::CN, "vs.zpr")];
+        let der = encode_dn(&rdns).unwrap();
+        let decoded = decode_dn(&der).unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                (DnAttr::O, "ZPR".to_string()),
+                (DnAttr::CN, "vs.zpr".to_string())
+            ]
+        );
+        // Not a single CN DN.
+        assert!(decode_dn_cn(&der).is_err());
+    }
+
+    #[test]
+    fn test_encode_dn_with_unit() {
+        let rdns = [
+            (DnAttr::O, "ZPR"),
+            (DnAttr::OU, "ops"),
+            (DnAttr::CN, "node.zpr"),
+        ];
+        let der = encode_dn(&rdns).unwrap();
+        let decoded = decode_dn(&der).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[1], (DnAttr::OU, "ops".to_string()));
+    }
+
+    #[test]
+    fn test_dn_equal_semantically_same() {
+        let a = encode_dn(&[(DnAttr::O, "ZPR"), (DnAttr::CN, "vs.zpr")]).unwrap();
+        let b = encode_dn(&[(DnAttr::CN, "VS.zpr"), (DnAttr::O, "  ZPR ")]).unwrap();
+        assert_ne!(a, b);
+        assert!(dn_equal(&a, &b).unwrap());
+
+        // Same name as a PrintableString rather than UTF8String.
+        let mut printable = VISA_SERVICE_DN.to_vec();
+        printable[11] = TAG_PRINTABLESTRING;
+        assert!(dn_equal(VISA_SERVICE_DN, &printable).unwrap());
+    }
+
+    #[test]
+    fn test_dn_equal_different() {
+        let a = encode_dn(&[(DnAttr::O, "ZPR"), (DnAttr::CN, "vs.zpr")]).unwrap();
+        let b = encode_dn(&[(DnAttr::O, "ZPR"), (DnAttr::CN, "node.zpr")]).unwrap();
+        assert!(!dn_equal(&a, &b).unwrap());
+        // Same CN but the value moved to a different attribute type.
+        let c = encode_dn(&[(DnAttr::OU, "ZPR"), (DnAttr::CN, "vs.zpr")]).unwrap();
+        assert!(!dn_equal(&a, &c).unwrap());
+        // Extra RDN.
+        assert!(!dn_equal(&a, VISA_SERVICE_DN).unwrap());
+        // Malformed input is an error, not "not equal".
+        assert!(dn_equal(&a, &[0x30]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Crate level error type, for callers that work across several of our modules
+//! and want a single `Result` type. The per-module errors remain the primary
+//! error types; this just wraps them.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ZprCommonError {
+    #[error("DN error: {0}")]
+    Dn(#[from] crate::dn::DnError),
+
+    #[error("packet info error: {0}")]
+    PacketInfo(#[from] crate::packet_info::PacketInfoError),
+
+    #[cfg(feature = "vsapi")]
+    #[error("vsapi type error: {0}")]
+    Vsapi(#[from] crate::vsapi_types::VsapiTypeError),
+
+    #[cfg(feature = "policy")]
+    #[error("policy type error: {0}")]
+    Policy(#[from] crate::policy_types::PolicyTypeError),
+}
+
+#[cfg(all(test, feature = "vsapi", feature = "policy"))]
+mod tests {
+    use super::*;
+    use crate::policy_types::PolicyTypeError;
+    use crate::vsapi_types::VsapiTypeError;
+
+    fn vsapi_fails() -> Result<(), VsapiTypeError> {
+        Err(VsapiTypeError::DeserializationError("vsapi"))
+    }
+
+    fn policy_fails() -> Result<(), PolicyTypeError> {
+        Err(PolicyTypeError::DeserializationError("policy"))
+    }
+
+    fn call_vsapi() -> Result<(), ZprCommonError> {
+        vsapi_fails()?;
+        Ok(())
+    }
+
+    fn call_policy() -> Result<(), ZprCommonError> {
+        policy_fails()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_errors_convert() {
+        assert!(matches!(call_vsapi(), Err(ZprCommonError::Vsapi(_))));
+        assert!(matches!(call_policy(), Err(ZprCommonError::Policy(_))));
+    }
+}
@@ -1,7 +1,16 @@
 //! ZPR concepts, excluding the ZDP protocol.
+//!
+//! With default features off the crate is `no_std` (it still needs `alloc`) and
+//! only has the pure data modules: [addrs], [dn], [error], [packet_info] and
+//! [write_to].
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 pub mod addrs;
 pub mod dn;
+pub mod error;
 pub mod packet_info;
 pub mod write_to;
 
@@ -1,10 +1,19 @@
+use core::net::IpAddr;
 use open_enum::open_enum;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use thiserror::Error;
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
 
+/// Errors from checked conversions of packet info types.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PacketInfoError {
+    #[error("unknown L3 type: {0}")]
+    UnknownL3Type(u8),
+}
+
 /// Substrate Address
-pub type SubstrateAddr = std::net::SocketAddr;
+pub type SubstrateAddr = core::net::SocketAddr;
 
 /// ZPR Parameter Index
 pub type Zpi = u8;
@@ -73,19 +82,9 @@ pub const KM_ID_NULL: KmId = 0;
 /// ZPR actor packet L3 type (RFC 6.5 § 6.3.11)
 #[open_enum]
 #[derive(
-    Copy,
-    Clone,
-    Debug,
-    Default,
-    FromBytes,
-    Hash,
-    IntoBytes,
-    Immutable,
-    KnownLayout,
-    Unaligned,
-    Serialize,
-    Deserialize,
+    Copy, Clone, Debug, Default, FromBytes, Hash, IntoBytes, Immutable, KnownLayout, Unaligned,
 )]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum L3Type {
     Ipv4 = 4,
@@ -93,6 +92,20 @@ pub enum L3Type {
 }
 
 impl L3Type {
+    /// The wire byte.
+    pub const fn as_u8(&self) -> u8 {
+        self.0
+    }
+
+    /// Parse a wire byte, accepting only the known types (4 and 6).
+    pub fn try_from_u8(v: u8) -> Result<L3Type, PacketInfoError> {
+        match L3Type(v) {
+            L3Type::Ipv4 => Ok(L3Type::Ipv4),
+            L3Type::Ipv6 => Ok(L3Type::Ipv6),
+            _ => Err(PacketInfoError::UnknownL3Type(v)),
+        }
+    }
+
     pub fn new_from_addr(addr: &IpAddr) -> Self {
         match addr {
             IpAddr::V4(_) => L3Type::Ipv4,
@@ -101,8 +114,8 @@ impl L3Type {
     }
 }
 
-impl std::fmt::Display for L3Type {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl core::fmt::Display for L3Type {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         match *self {
             Self::Ipv4 => write!(f, "IPv4"),
             Self::Ipv6 => write!(f, "IPv6"),
@@ -137,6 +150,27 @@ pub mod compression_mode {
     pub const DESTINATION_PORT_PRESENT: CompressionMode = 0x20;
     pub const SOURCE_PORT_PRESENT: CompressionMode = 0x40;
     //pub const IP_PROTOCOL_PRESENT: CompressionMode = 0x80; // FIXME: this seems unused; I have a Q out to Frank about it
+
+    /// The mode flags for which of the source and destination ports are present.
+    pub const fn from_ports(src_present: bool, dst_present: bool) -> CompressionMode {
+        let mut mode = 0;
+        if src_present {
+            mode |= SOURCE_PORT_PRESENT;
+        }
+        if dst_present {
+            mode |= DESTINATION_PORT_PRESENT;
+        }
+        mode
+    }
+
+    /// Which of the `(source, destination)` ports are present in `mode`. Inverse
+    /// of [from_ports]; other bits are ignored.
+    pub const fn ports_present(mode: CompressionMode) -> (bool, bool) {
+        (
+            mode & SOURCE_PORT_PRESENT != 0,
+            mode & DESTINATION_PORT_PRESENT != 0,
+        )
+    }
 }
 
 /// Traffic classification specification type.
@@ -147,11 +181,43 @@ pub enum Tcst {
     Ip5Tuple = 0,
 }
 
-impl std::fmt::Display for Tcst {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl core::fmt::Display for Tcst {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         match *self {
             Self::Ip5Tuple => write!(f, "IP 5-Tuple"),
             other => write!(f, "[unknown TCST {}]", other.0),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_l3_type_u8() {
+        assert_eq!(L3Type::Ipv4.as_u8(), 4);
+        assert_eq!(L3Type::try_from_u8(4), Ok(L3Type::Ipv4));
+        assert_eq!(L3Type::try_from_u8(6), Ok(L3Type::Ipv6));
+        assert_eq!(
+            L3Type::try_from_u8(7),
+            Err(PacketInfoError::UnknownL3Type(7))
+        );
+        assert_eq!(L3Type::try_from_u8(L3Type::Ipv6.as_u8()), Ok(L3Type::Ipv6));
+    }
+
+    #[test]
+    fn test_compression_mode_ports_round_trip() {
+        for src in [false, true] {
+            for dst in [false, true] {
+                let mode = compression_mode::from_ports(src, dst);
+                assert_eq!(compression_mode::ports_present(mode), (src, dst));
+            }
+        }
+        assert_eq!(
+            compression_mode::from_ports(true, true),
+            compression_mode::SOURCE_PORT_PRESENT | compression_mode::DESTINATION_PORT_PRESENT
+        );
+        assert_eq!(compression_mode::ports_present(0x80), (false, false));
+    }
+}
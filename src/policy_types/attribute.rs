@@ -1,6 +1,9 @@
 use crate::policy_types::error::AttributeError;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Write;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
 
 pub const ATTR_DOMAIN_SERVICE: &str = "service";
 pub const ATTR_DOMAIN_USER: &str = "user";
@@ -10,21 +13,23 @@ pub const ATTR_DOMAIN_ZPR_INTERNAL: &str = "zpr";
 
 /// A ZPL attribute. Could be a tuple type attribute, eg "user.role:marketing" or a
 /// tag type.  An attribute may be optional or required, and may be multi-valued
-/// or single-valued.
+/// or single-valued.  When used as a condition a negated attribute matches when
+/// the value is not equal (or the attribute is absent).
 #[derive(Debug, Clone, PartialEq)]
 pub struct Attribute {
     domain: AttrDomain,
     name: String, // For a tag this is the tag name, else this is the attribute name.
     values: Option<Vec<String>>, // For a tag, this is always None.
-    attr_type: AttrT,
+    attr_type: AttrKind,
     pub optional: bool,
+    pub negated: bool,
 }
 
 /// An attribute must live in one of our domains. When parsing sometimes we
 /// end up in an intermediate state where we don't know the domain yet so
 /// we use `Unspecified`.  An error will occur if we try to write policy
 /// and there remain any unspecified domains.
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy)]
 pub enum AttrDomain {
     Unspecified,
     Endpoint,
@@ -47,8 +52,9 @@ impl fmt::Display for AttrDomain {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum AttrT {
+/// The kind of an attribute: a tag, or a tuple holding one or many values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AttrKind {
     Tag,
     SingleValued,
     MultiValued,
@@ -66,14 +72,16 @@ pub enum DomainFallback {
 pub struct TagAttrBuilder {
     raw_name: String,
     optional: bool,
+    negated: bool,
     domain_fb: DomainFallback,
 }
 
 pub struct TupleAttrBuilder {
     raw_name: String,
-    attr_type: AttrT,
+    attr_type: AttrKind,
     values: Option<Vec<String>>,
     optional: bool,
+    negated: bool,
     domain_fb: DomainFallback,
 }
 
@@ -83,6 +91,7 @@ impl TagAttrBuilder {
         TagAttrBuilder {
             raw_name: name.into(),
             optional: false,
+            negated: false,
             domain_fb: DomainFallback::ErrorIfMissing,
         }
     }
@@ -92,6 +101,11 @@ impl TagAttrBuilder {
         self
     }
 
+    pub fn negated(mut self, negated: bool) -> Self {
+        self.negated = negated;
+        self
+    }
+
     pub fn domain_hint(mut self, domain: AttrDomain) -> Self {
         self.domain_fb = DomainFallback::UseHint(domain);
         self
@@ -102,14 +116,20 @@ impl TagAttrBuilder {
         self
     }
 
+    /// Build the attribute without checking its values. A multi-valued attribute
+    /// built with no values has `None` values, which reads as a schema
+    /// declaration (`user.groups{}`), not as an empty set. Use
+    /// [TupleAttrBuilder::build_schema] or [TupleAttrBuilder::build_instance] to
+    /// make the intent explicit.
     pub fn build(self) -> Result<Attribute, AttributeError> {
         let (domain, name) = resolve_domain(&self.raw_name, self.domain_fb)?;
         Ok(Attribute {
             domain,
             name,
             values: None,
-            attr_type: AttrT::Tag,
+            attr_type: AttrKind::Tag,
             optional: self.optional,
+            negated: self.negated,
         })
     }
 }
@@ -119,29 +139,30 @@ impl TupleAttrBuilder {
     fn new<N: Into<String>>(name: N) -> Self {
         TupleAttrBuilder {
             raw_name: name.into(),
-            attr_type: AttrT::SingleValued,
+            attr_type: AttrKind::SingleValued,
             values: None,
             optional: false,
+            negated: false,
             domain_fb: DomainFallback::ErrorIfMissing,
         }
     }
 
     pub fn single(mut self) -> Self {
-        self.attr_type = AttrT::SingleValued;
+        self.attr_type = AttrKind::SingleValued;
         self
     }
 
     /// Note that single-valued is the default.
     pub fn multi(mut self) -> Self {
-        self.attr_type = AttrT::MultiValued;
+        self.attr_type = AttrKind::MultiValued;
         self
     }
 
     pub fn multi_if(mut self, multi: bool) -> Self {
         if multi {
-            self.attr_type = AttrT::MultiValued;
+            self.attr_type = AttrKind::MultiValued;
         } else {
-            self.attr_type = AttrT::SingleValued;
+            self.attr_type = AttrKind::SingleValued;
         }
         self
     }
@@ -151,11 +172,26 @@ impl TupleAttrBuilder {
         self
     }
 
+    pub fn negated(mut self, negated: bool) -> Self {
+        self.negated = negated;
+        self
+    }
+
     pub fn value<V: Into<String>>(mut self, v: V) -> Self {
         self.values = Some(vec![v.into()]);
         self
     }
 
+    /// Set a single integer value, stored in its canonical string form.
+    pub fn int_value(self, v: i64) -> Self {
+        self.value(v.to_string())
+    }
+
+    /// Set a single boolean value, stored as "true" or "false".
+    pub fn bool_value(self, v: bool) -> Self {
+        self.value(v.to_string())
+    }
+
     /// If you sent more than one value the resulting tuple will be
     /// multi-valued type (you do not need to explicitly call `multi()`).
     pub fn values(mut self, vals: Vec<String>) -> Self {
@@ -183,9 +219,9 @@ impl TupleAttrBuilder {
     pub fn build(self) -> Result<Attribute, AttributeError> {
         let (domain, name) = resolve_domain(&self.raw_name, self.domain_fb)?;
         let attr_type = match (&self.values, self.attr_type) {
-            (_, AttrT::MultiValued) => AttrT::MultiValued, // explicitly set by caller
-            (Some(v), AttrT::SingleValued) if v.len() > 1 => AttrT::MultiValued, // inferred from values
-            _ => AttrT::SingleValued,
+            (_, AttrKind::MultiValued) => AttrKind::MultiValued, // explicitly set by caller
+            (Some(v), AttrKind::SingleValued) if v.len() > 1 => AttrKind::MultiValued, // inferred from values
+            _ => AttrKind::SingleValued,
         };
         Ok(Attribute {
             domain,
@@ -193,8 +229,55 @@ impl TupleAttrBuilder {
             values: self.values,
             attr_type,
             optional: self.optional,
+            negated: self.negated,
         })
     }
+
+    /// Build a schema declaration. Values are optional: a multi-valued
+    /// declaration with no values declares the key only.
+    pub fn build_schema(self) -> Result<Attribute, AttributeError> {
+        self.build()
+    }
+
+    /// Build an attribute instance, which must carry values. A single-valued
+    /// instance needs exactly one value. A multi-valued instance may have an
+    /// empty set of values, but it must be set explicitly (eg `values(vec![])`).
+    pub fn build_instance(self) -> Result<Attribute, AttributeError> {
+        let a = self.build()?;
+        match (&a.values, a.attr_type) {
+            (None, _) => Err(AttributeError::InvalidOperation(format!(
+                "attribute instance {} has no values",
+                a.zplc_key()
+            ))),
+            (Some(v), AttrKind::SingleValued) if v.len() != 1 => {
+                Err(AttributeError::InvalidOperation(format!(
+                    "single-valued attribute instance {} needs exactly one value",
+                    a.zplc_key()
+                )))
+            }
+            _ => Ok(a),
+        }
+    }
+}
+
+/// Split the value part of a schema string (the bit after the ':').
+fn parse_schema_values(v: &str) -> Vec<String> {
+    match v
+        .strip_prefix('{')
+        .and_then(|inner| inner.strip_suffix('}'))
+    {
+        Some("") => Vec::new(),
+        Some(inner) => inner.split(", ").map(String::from).collect(),
+        None => vec![v.to_string()],
+    }
+}
+
+/// Like [Attribute::parse_domain] but also accepts the ZPR internal domain.
+fn parse_schema_domain(key: &str) -> Result<(AttrDomain, String), AttributeError> {
+    match key.strip_prefix(&format!("{}.", ATTR_DOMAIN_ZPR_INTERNAL)) {
+        Some(name) => Ok((AttrDomain::ZprInternal, name.to_string())),
+        None => Attribute::parse_domain(key),
+    }
 }
 
 fn resolve_domain(name: &str, fb: DomainFallback) -> Result<(AttrDomain, String), AttributeError> {
@@ -222,32 +305,90 @@ impl Attribute {
 
     /// String form of the attribute that also includes the schema hints like
     /// the '{}' suffix for multi-valued and '?' for optional.
+    ///
+    /// The form is `[!][#]domain.name[{}][?][:value | :{v1, v2}]`, where an empty set of
+    /// values is written as `:{}`. This is the inverse of [Attribute::from_schema_string].
     pub fn to_schema_string(&self) -> String {
         let mut f = String::new();
         let key = format!("{}.{}", self.domain, self.name);
 
+        if self.negated {
+            write!(f, "!").unwrap();
+        }
         if self.is_tag() {
             write!(f, "#{}", key).unwrap();
-        } else if let Some(v) = &self.values {
-            if v.is_empty() {
-                write!(f, "{key}:").unwrap();
-            } else if v.len() == 1 {
-                write!(f, "{key}:{}", v[0]).unwrap();
-            } else {
-                write!(f, "{key}:{{{}}}", v.join(", ")).unwrap();
-            }
         } else {
             write!(f, "{}", key).unwrap();
             if self.is_multi_valued() {
                 write!(f, "{{}}").unwrap();
             }
-            if self.optional {
-                write!(f, "?").unwrap();
+        }
+        if self.optional {
+            write!(f, "?").unwrap();
+        }
+        if let Some(v) = &self.values {
+            if v.len() == 1 {
+                write!(f, ":{}", v[0]).unwrap();
+            } else {
+                write!(f, ":{{{}}}", v.join(", ")).unwrap();
             }
         }
         f
     }
 
+    /// Parse the output of [Attribute::to_schema_string] back into an attribute.
+    ///
+    /// Values are separated by ", " inside braces, so values that themselves
+    /// contain ", " or are wrapped in braces do not survive the round trip.
+    pub fn from_schema_string(s: &str) -> Result<Self, AttributeError> {
+        let s = s.trim();
+        let (negated, rest) = match s.strip_prefix('!') {
+            Some(r) => (true, r),
+            None => (false, s),
+        };
+        let (is_tag, rest) = match rest.strip_prefix('#') {
+            Some(r) => (true, r),
+            None => (false, rest),
+        };
+        let (key, values) = match rest.split_once(':') {
+            Some((k, v)) => (k, Some(parse_schema_values(v))),
+            None => (rest, None),
+        };
+        let (key, optional) = match key.strip_suffix('?') {
+            Some(k) => (k, true),
+            None => (key, false),
+        };
+        let (key, multi) = match key.strip_suffix("{}") {
+            Some(k) => (k, true),
+            None => (key, false),
+        };
+        let (domain, name) = parse_schema_domain(key)?;
+        if name.is_empty() {
+            return Err(AttributeError::ParseError(s.to_string()));
+        }
+
+        let attr_type = if is_tag {
+            if multi || values.is_some() {
+                return Err(AttributeError::ParseError(format!(
+                    "tag may not have values: {s}"
+                )));
+            }
+            AttrKind::Tag
+        } else if multi || values.as_ref().is_some_and(|v| v.len() > 1) {
+            AttrKind::MultiValued
+        } else {
+            AttrKind::SingleValued
+        };
+        Ok(Attribute {
+            domain,
+            name,
+            values,
+            attr_type,
+            optional,
+            negated,
+        })
+    }
+
     /// String form of the attribute without the additional schema hints.
     pub fn to_instance_string(&self) -> String {
         let mut f = String::new();
@@ -326,22 +467,71 @@ impl Attribute {
         new_a
     }
 
+    /// A clone of this attribute with the domain replaced and everything else,
+    /// including the name, kept. See [Attribute::set_domain] to change in place.
+    pub fn with_domain(&self, domain: AttrDomain) -> Self {
+        let mut new_a = self.clone();
+        new_a.domain = domain;
+        new_a
+    }
+
+    /// Key for a deterministic ordering of attributes: by domain, name and kind,
+    /// with the values and flags breaking any remaining ties.
+    pub(crate) fn sort_key(&self) -> (AttrDomain, &str, AttrKind, Option<&[String]>, bool, bool) {
+        (
+            self.domain,
+            &self.name,
+            self.attr_type,
+            self.values.as_deref(),
+            self.optional,
+            self.negated,
+        )
+    }
+
+    pub fn kind(&self) -> AttrKind {
+        self.attr_type
+    }
+
     pub fn is_tag(&self) -> bool {
-        self.attr_type == AttrT::Tag
+        self.attr_type == AttrKind::Tag
     }
 
     pub fn is_single_valued(&self) -> bool {
-        self.attr_type == AttrT::SingleValued
+        self.attr_type == AttrKind::SingleValued
     }
 
     pub fn is_multi_valued(&self) -> bool {
-        self.attr_type == AttrT::MultiValued
+        self.attr_type == AttrKind::MultiValued
     }
 
     pub fn get_values(&self) -> Option<&[String]> {
         self.values.as_deref()
     }
 
+    /// The sole value, if there is exactly one.
+    fn single_value(&self) -> Option<&str> {
+        match self.values.as_deref() {
+            Some([v]) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer. None for a tag, if there is not exactly one value,
+    /// or if the value is not an integer.
+    pub fn as_i64(&self) -> Option<i64> {
+        self.single_value()?.parse().ok()
+    }
+
+    /// The value as a boolean. None for a tag, if there is not exactly one value,
+    /// or if the value is not exactly "true" or "false".
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.single_value()? {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+
     pub fn set_multi_valued(&mut self) -> Result<(), AttributeError> {
         if self.is_tag() {
             return Err(AttributeError::InvalidOperation(format!(
@@ -349,7 +539,7 @@ impl Attribute {
                 self.zplc_key()
             )));
         }
-        self.attr_type = AttrT::MultiValued;
+        self.attr_type = AttrKind::MultiValued;
         Ok(())
     }
 
@@ -396,6 +586,26 @@ impl Attribute {
         }
     }
 
+    /// The part of [Attribute::zpl_key] after the domain.
+    fn zpl_key_name(&self) -> &str {
+        if self.is_tag() { "zpr.tag" } else { &self.name }
+    }
+
+    /// True if both attributes have the same [Attribute::zpl_key]. Values are
+    /// ignored. Unlike comparing the keys this does not allocate.
+    pub fn key_eq(&self, other: &Attribute) -> bool {
+        self.domain == other.domain && self.zpl_key_name() == other.zpl_key_name()
+    }
+
+    /// A hash of [Attribute::zpl_key], consistent with [Attribute::key_eq]. Does
+    /// not allocate. The hash is only stable within a single process.
+    pub fn key_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.domain.hash(&mut hasher);
+        self.zpl_key_name().hash(&mut hasher);
+        hasher.finish()
+    }
+
     /// The ZPL value for this attribute. If there is no value an empty string is returned.
     /// If there are multiple values a comma separated list is returned.
     pub fn zpl_value(&self) -> String {
@@ -441,6 +651,31 @@ impl Attribute {
     }
 }
 
+impl FromStr for Attribute {
+    type Err = AttributeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Attribute::from_schema_string(s)
+    }
+}
+
+/// The attributes in `attrs` that are in `domain`, in order.
+pub fn attributes_in_domain(
+    attrs: &[Attribute],
+    domain: AttrDomain,
+) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(move |a| a.is_domain(domain))
+}
+
+/// Group `attrs` by domain. Within each domain the attributes keep their order.
+pub fn partition_by_domain(attrs: &[Attribute]) -> BTreeMap<AttrDomain, Vec<&Attribute>> {
+    let mut map: BTreeMap<AttrDomain, Vec<&Attribute>> = BTreeMap::new();
+    for a in attrs {
+        map.entry(a.domain).or_default().push(a);
+    }
+    map
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -618,4 +853,270 @@ mod test {
         let a2 = Attribute::try_zpr_internal_attr_mv("roles", "admin");
         assert!(a2.is_err());
     }
+
+    #[test]
+    fn test_schema_string_round_trip_matrix() {
+        let value_sets: Vec<Option<Vec<String>>> = vec![
+            None,
+            Some(vec![]),
+            Some(vec!["".to_string()]),
+            Some(vec!["admin".to_string()]),
+            Some(vec!["admin".to_string(), "ops".to_string()]),
+        ];
+        for (optional, negated) in [(false, false), (true, false), (false, true), (true, true)] {
+            for domain in ["user", "endpoint", "service", "link"] {
+                let tag = Attribute::tag(format!("{domain}.red"))
+                    .optional(optional)
+                    .negated(negated)
+                    .build()
+                    .unwrap();
+                let parsed = Attribute::from_schema_string(&tag.to_schema_string()).unwrap();
+                assert_eq!(parsed, tag, "{}", tag.to_schema_string());
+
+                for multi in [false, true] {
+                    for values in &value_sets {
+                        let a = Attribute::tuple(format!("{domain}.role"))
+                            .multi_if(multi)
+                            .optional(optional)
+                            .negated(negated)
+                            .values_opt(values.clone())
+                            .build()
+                            .unwrap();
+                        let s = a.to_schema_string();
+                        assert_eq!(Attribute::from_schema_string(&s).unwrap(), a, "{s}");
+                        assert_eq!(s.parse::<Attribute>().unwrap(), a, "{s}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_string_round_trip_internal() {
+        let a = Attribute::try_zpr_internal_attr_mv("zpr.roles", "admin").unwrap();
+        assert_eq!("zpr.roles{}:admin", a.to_schema_string());
+        assert_eq!(
+            Attribute::from_schema_string(&a.to_schema_string()).unwrap(),
+            a
+        );
+    }
+
+    #[test]
+    fn test_schema_string_optional_with_values() {
+        let a = Attribute::tuple("user.groups")
+            .optional(true)
+            .values(vec!["a".to_string(), "b".to_string()])
+            .build()
+            .unwrap();
+        assert_eq!("user.groups{}?:{a, b}", a.to_schema_string());
+    }
+
+    #[test]
+    fn test_from_schema_string_errors() {
+        assert!(Attribute::from_schema_string("nodomain").is_err());
+        assert!(Attribute::from_schema_string("user.").is_err());
+        assert!(Attribute::from_schema_string("#user.red:x").is_err());
+        assert!(Attribute::from_schema_string("#user.red{}").is_err());
+    }
+
+    #[test]
+    fn test_kind_matches_predicates() {
+        let attrs = vec![
+            Attribute::tag("user.red").build().unwrap(),
+            Attribute::tuple("user.role")
+                .value("admin")
+                .build()
+                .unwrap(),
+            Attribute::tuple("user.groups").multi().build().unwrap(),
+            Attribute::tuple("user.groups")
+                .values(vec!["a".to_string(), "b".to_string()])
+                .build()
+                .unwrap(),
+        ];
+        let kinds: Vec<AttrKind> = attrs.iter().map(|a| a.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AttrKind::Tag,
+                AttrKind::SingleValued,
+                AttrKind::MultiValued,
+                AttrKind::MultiValued
+            ]
+        );
+        for a in &attrs {
+            assert_eq!(a.is_tag(), a.kind() == AttrKind::Tag);
+            assert_eq!(a.is_single_valued(), a.kind() == AttrKind::SingleValued);
+            assert_eq!(a.is_multi_valued(), a.kind() == AttrKind::MultiValued);
+        }
+    }
+
+    #[test]
+    fn test_zpr_internal_attr_missing_prefix_is_error() {
+        for name in ["role", "user.role", "zprrole"] {
+            assert!(matches!(
+                Attribute::try_zpr_internal_attr(name, "admin"),
+                Err(AttributeError::InvalidPrefix(_))
+            ));
+            assert!(matches!(
+                Attribute::try_zpr_internal_attr_mv(name, "admin"),
+                Err(AttributeError::InvalidPrefix(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let level = Attribute::tuple("user.level")
+            .int_value(-5)
+            .build()
+            .unwrap();
+        assert_eq!(level.get_values(), Some(&["-5".to_string()][..]));
+        assert_eq!(level.as_i64(), Some(-5));
+        assert_eq!(level.as_bool(), None);
+
+        let trusted = Attribute::tuple("endpoint.trusted")
+            .bool_value(true)
+            .build()
+            .unwrap();
+        assert_eq!(trusted.as_bool(), Some(true));
+        assert_eq!(trusted.as_i64(), None);
+
+        // Typed values survive the string wire form.
+        let parsed: Attribute = level.to_schema_string().parse().unwrap();
+        assert_eq!(parsed.as_i64(), Some(-5));
+        let parsed: Attribute = "endpoint.trusted:false".parse().unwrap();
+        assert_eq!(parsed.as_bool(), Some(false));
+
+        let not_typed = Attribute::tuple("user.role").value("True").build().unwrap();
+        assert_eq!(not_typed.as_bool(), None);
+        assert_eq!(not_typed.as_i64(), None);
+        let multi: Attribute = "user.levels:{1, 2}".parse().unwrap();
+        assert_eq!(multi.as_i64(), None);
+        let tag = Attribute::tag("endpoint.hardened").build().unwrap();
+        assert_eq!(tag.as_bool(), None);
+    }
+
+    #[test]
+    fn test_partition_by_domain() {
+        let attrs: Vec<Attribute> = [
+            "user.role:admin",
+            "#endpoint.hardened",
+            "user.group:{eng, ops}",
+            "service.name:web",
+            "#user.trusted",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+
+        let user: Vec<&str> = attributes_in_domain(&attrs, AttrDomain::User)
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(user, vec!["role", "group", "trusted"]);
+        assert_eq!(attributes_in_domain(&attrs, AttrDomain::Link).count(), 0);
+
+        let parts = partition_by_domain(&attrs);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[&AttrDomain::User].len(), 3);
+        assert_eq!(parts[&AttrDomain::Endpoint][0].name, "hardened");
+        assert_eq!(parts[&AttrDomain::Service][0].name, "name");
+        assert!(!parts.contains_key(&AttrDomain::Link));
+        let total: usize = parts.values().map(|v| v.len()).sum();
+        assert_eq!(total, attrs.len());
+    }
+
+    #[test]
+    fn test_attribute_key_eq() {
+        let a = Attribute::tuple("user.role")
+            .value("admin")
+            .build()
+            .unwrap();
+        let b = Attribute::tuple("user.role").value("ops").build().unwrap();
+        assert_ne!(a, b);
+        assert!(a.key_eq(&b));
+        assert_eq!(a.key_hash(), b.key_hash());
+        assert_eq!(a.zpl_key(), b.zpl_key());
+
+        let other_domain = Attribute::tuple("endpoint.role")
+            .value("admin")
+            .build()
+            .unwrap();
+        assert!(!a.key_eq(&other_domain));
+        let other_name = Attribute::tuple("user.team")
+            .value("admin")
+            .build()
+            .unwrap();
+        assert!(!a.key_eq(&other_name));
+
+        // All tags in a domain share the "<domain>.zpr.tag" key.
+        let t1 = Attribute::tag("user.vip").build().unwrap();
+        let t2 = Attribute::tag("user.staff").build().unwrap();
+        assert!(t1.key_eq(&t2));
+        assert_eq!(t1.key_hash(), t2.key_hash());
+        assert!(!t1.key_eq(&a));
+        assert!(!t1.key_eq(&Attribute::tag("endpoint.vip").build().unwrap()));
+    }
+
+    #[test]
+    fn test_attribute_with_domain() {
+        let a = Attribute::tuple("role")
+            .value("admin")
+            .allow_unspecified()
+            .build()
+            .unwrap();
+        assert!(a.is_unspecified_domain());
+
+        let u = a.with_domain(AttrDomain::User);
+        assert!(u.is_domain(AttrDomain::User));
+        assert_eq!(u.zpl_key(), "user.role");
+        assert_eq!(u.zpl_value(), "admin");
+        // The original is unchanged.
+        assert!(a.is_unspecified_domain());
+    }
+
+    #[test]
+    fn test_tuple_build_schema_vs_instance() {
+        // A schema declaration needs no values.
+        let decl = Attribute::tuple("user.groups")
+            .multi()
+            .build_schema()
+            .unwrap();
+        assert!(decl.is_multi_valued());
+        assert_eq!(decl.get_values(), None);
+        assert_eq!(decl.to_schema_string(), "user.groups{}");
+
+        // An instance without values is ambiguous and rejected.
+        assert!(matches!(
+            Attribute::tuple("user.groups").multi().build_instance(),
+            Err(AttributeError::InvalidOperation(_))
+        ));
+
+        // An explicitly empty set is a valid instance.
+        let empty = Attribute::tuple("user.groups")
+            .multi()
+            .values(vec![])
+            .build_instance()
+            .unwrap();
+        assert_eq!(empty.get_values(), Some(&[][..]));
+        assert_eq!(empty.to_instance_string(), "user.groups:");
+
+        let groups = Attribute::tuple("user.groups")
+            .values(vec!["a".to_string(), "b".to_string()])
+            .build_instance()
+            .unwrap();
+        assert!(groups.is_multi_valued());
+
+        assert!(
+            Attribute::tuple("user.role")
+                .value("admin")
+                .build_instance()
+                .is_ok()
+        );
+        assert!(
+            Attribute::tuple("user.role")
+                .values(vec![])
+                .build_instance()
+                .is_err()
+        );
+    }
 }
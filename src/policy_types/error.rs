@@ -34,4 +34,10 @@ pub enum PolicyTypeError {
 
     #[error("IP address parse error: {0}")]
     AddrParseError(#[from] std::net::AddrParseError),
+
+    #[error("parse error: {0}")]
+    ParseError(String),
+
+    #[error("Validation error: {0}")]
+    ValidationError(String),
 }
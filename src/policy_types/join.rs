@@ -3,6 +3,9 @@ use crate::policy_types::attribute::Attribute;
 use crate::policy_types::error::PolicyTypeError;
 use crate::policy_types::writer::write_attributes;
 use crate::write_to::WriteTo;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
 
 pub struct JoinPolicy {
     pub conditions: Vec<Attribute>,
@@ -26,6 +29,11 @@ pub struct Scope {
     pub port_range: Option<(u16, u16)>,
 }
 
+// IP protocol numbers that scope flags apply to.
+const PROTO_ICMP: u8 = 1;
+const PROTO_UDP: u8 = 17;
+const PROTO_IPV6_ICMP: u8 = 58;
+
 /// This scope flag mirrors what is in the capnp schema.
 #[derive(PartialEq, Eq, Debug)]
 #[allow(dead_code)]
@@ -45,6 +53,40 @@ pub enum ServiceType {
     BuiltIn, // eg, node access to VS, or VS access to VSS
 }
 
+impl fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceType::Undefined => write!(f, "undefined"),
+            ServiceType::Trusted(api) => write!(f, "trusted({api})"),
+            ServiceType::Authentication => write!(f, "authentication"),
+            ServiceType::Visa => write!(f, "visa"),
+            ServiceType::Regular => write!(f, "regular"),
+            ServiceType::BuiltIn => write!(f, "builtin"),
+        }
+    }
+}
+
+/// Parses the [Display](fmt::Display) form, eg "regular" or "trusted(my-api)".
+impl FromStr for ServiceType {
+    type Err = PolicyTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "undefined" => Ok(ServiceType::Undefined),
+            "authentication" => Ok(ServiceType::Authentication),
+            "visa" => Ok(ServiceType::Visa),
+            "regular" => Ok(ServiceType::Regular),
+            "builtin" => Ok(ServiceType::BuiltIn),
+            _ => match s.strip_prefix("trusted(").and_then(|r| r.strip_suffix(')')) {
+                Some(api) if !api.is_empty() => Ok(ServiceType::Trusted(api.to_string())),
+                _ => Err(PolicyTypeError::ParseError(format!(
+                    "unknown service type: {s}"
+                ))),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Copy)]
 pub struct PFlags {
     pub node: bool,
@@ -103,6 +145,112 @@ impl TryFrom<v1::scope::Reader<'_>> for Scope {
     }
 }
 
+impl ScopeFlag {
+    /// True if the flag may be used on a scope with the given IP protocol.
+    pub fn allowed_for(&self, protocol: u8) -> bool {
+        match self {
+            ScopeFlag::UdpOneWay => protocol == PROTO_UDP,
+            ScopeFlag::IcmpRequestReply => protocol == PROTO_ICMP || protocol == PROTO_IPV6_ICMP,
+        }
+    }
+}
+
+impl Scope {
+    /// Error if the flag does not apply to the protocol, eg `UdpOneWay` on TCP.
+    pub fn validate(&self) -> Result<(), PolicyTypeError> {
+        match &self.flag {
+            Some(flag) if !flag.allowed_for(self.protocol) => {
+                Err(PolicyTypeError::ValidationError(format!(
+                    "scope flag {:?} not valid for protocol {}",
+                    flag, self.protocol
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// True if `port` is the scope's single port or within its range. A scope
+    /// with neither a port nor a range matches any port.
+    pub fn port_match(&self, port: u16) -> bool {
+        self.port_intervals()
+            .iter()
+            .any(|(lo, hi)| (*lo..=*hi).contains(&port))
+    }
+
+    /// The inclusive port ranges this scope covers. A scope with neither a port
+    /// nor a range covers every port.
+    fn port_intervals(&self) -> Vec<(u16, u16)> {
+        let mut intervals = Vec::with_capacity(2);
+        if let Some(port) = self.port {
+            intervals.push((port, port));
+        }
+        if let Some(range) = self.port_range {
+            intervals.push(range);
+        }
+        if intervals.is_empty() {
+            intervals.push((0, u16::MAX));
+        }
+        intervals
+    }
+
+    fn overlaps(&self, other: &Scope) -> bool {
+        self.protocol == other.protocol
+            && self.port_intervals().iter().any(|(lo, hi)| {
+                other
+                    .port_intervals()
+                    .iter()
+                    .any(|(olo, ohi)| lo <= ohi && olo <= hi)
+            })
+    }
+}
+
+impl Service {
+    /// Error if any two of the service's scopes for the same protocol cover a
+    /// common port, eg port 80 and range 79-81.
+    pub fn check_scope_overlap(&self) -> Result<(), PolicyTypeError> {
+        for (i, a) in self.endpoints.iter().enumerate() {
+            for b in &self.endpoints[i + 1..] {
+                if a.overlaps(b) {
+                    return Err(PolicyTypeError::ValidationError(format!(
+                        "service {} has overlapping scopes for protocol {}",
+                        self.id, a.protocol
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl JoinPolicy {
+    /// Check the policy for authoring mistakes that would make enforcement
+    /// ambiguous.
+    pub fn validate(&self) -> Result<(), PolicyTypeError> {
+        for service in self.provides.iter().flatten() {
+            for scope in &service.endpoints {
+                scope.validate()?;
+            }
+            service.check_scope_overlap()?;
+        }
+        Ok(())
+    }
+
+    /// The [Attribute::zpl_key] of every match condition, eg to find the policies
+    /// affected by renaming an attribute. Services carry no conditions of their
+    /// own, so only the match conditions contribute.
+    pub fn referenced_keys(&self) -> BTreeSet<String> {
+        self.conditions.iter().map(Attribute::zpl_key).collect()
+    }
+
+    /// Put the conditions into a deterministic order (by domain, name and kind) so
+    /// that equivalent policies serialize to the same bytes. The writer does not
+    /// reorder conditions, so call this first if the output is hashed or compared.
+    pub fn sort_conditions(&mut self) {
+        self.conditions
+            .sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    }
+}
+
 impl PFlags {
     /// Create the set of flags for a node.
     pub fn node(is_vs_dock: bool) -> PFlags {
@@ -217,3 +365,205 @@ fn write_services(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_types::attribute::AttrDomain;
+
+    #[test]
+    fn test_service_type_display() {
+        assert_eq!(
+            ServiceType::Trusted("my-api".to_string()).to_string(),
+            "trusted(my-api)"
+        );
+        assert_eq!(ServiceType::BuiltIn.to_string(), "builtin");
+        assert_eq!(ServiceType::Undefined.to_string(), "undefined");
+    }
+
+    #[test]
+    fn test_service_type_round_trip() {
+        let all = vec![
+            ServiceType::Undefined,
+            ServiceType::Trusted("my-api".to_string()),
+            ServiceType::Authentication,
+            ServiceType::Visa,
+            ServiceType::Regular,
+            ServiceType::BuiltIn,
+        ];
+        for st in all {
+            assert_eq!(st.to_string().parse::<ServiceType>().unwrap(), st);
+        }
+    }
+
+    #[test]
+    fn test_service_type_parse_errors() {
+        assert!("bogus".parse::<ServiceType>().is_err());
+        assert!("trusted()".parse::<ServiceType>().is_err());
+        assert!("trusted(my-api".parse::<ServiceType>().is_err());
+    }
+
+    fn policy_bytes(policy: &JoinPolicy) -> Vec<u8> {
+        let mut msg = capnp::message::Builder::new_default();
+        {
+            let mut root: v1::j_policy::Builder<'_> = msg.init_root();
+            policy.write_to(&mut root);
+        }
+        capnp::serialize::write_message_to_words(&msg)
+    }
+
+    #[test]
+    fn test_sort_conditions_stable_output() {
+        let role = Attribute::tuple("user.role")
+            .value("admin")
+            .build()
+            .unwrap();
+        let hardened = Attribute::tag("endpoint.hardened").build().unwrap();
+        let groups = Attribute::tuple("user.groups").multi().build().unwrap();
+
+        let mut p1 = JoinPolicy {
+            conditions: vec![role.clone(), hardened.clone(), groups.clone()],
+            flags: PFlags::default(),
+            provides: None,
+        };
+        let mut p2 = JoinPolicy {
+            conditions: vec![groups, role, hardened],
+            flags: PFlags::default(),
+            provides: None,
+        };
+        assert_ne!(policy_bytes(&p1), policy_bytes(&p2));
+
+        p1.sort_conditions();
+        p2.sort_conditions();
+        assert_eq!(p1.conditions, p2.conditions);
+        assert_eq!(policy_bytes(&p1), policy_bytes(&p2));
+        assert!(p1.conditions[0].is_domain(AttrDomain::Endpoint));
+    }
+
+    #[test]
+    fn test_referenced_keys() {
+        let policy = JoinPolicy {
+            conditions: vec![
+                Attribute::tuple("user.role")
+                    .value("admin")
+                    .build()
+                    .unwrap(),
+                Attribute::tuple("user.role").value("ops").build().unwrap(),
+                Attribute::tuple("endpoint.os")
+                    .value("linux")
+                    .build()
+                    .unwrap(),
+                Attribute::tag("endpoint.hardened").build().unwrap(),
+            ],
+            flags: PFlags::default(),
+            provides: None,
+        };
+        let keys: Vec<String> = policy.referenced_keys().into_iter().collect();
+        assert_eq!(keys, vec!["endpoint.os", "endpoint.zpr.tag", "user.role"]);
+    }
+
+    fn port_scope(protocol: u8, port: u16) -> Scope {
+        Scope {
+            protocol,
+            flag: None,
+            port: Some(port),
+            port_range: None,
+        }
+    }
+
+    fn range_scope(protocol: u8, low: u16, high: u16) -> Scope {
+        Scope {
+            protocol,
+            flag: None,
+            port: None,
+            port_range: Some((low, high)),
+        }
+    }
+
+    #[test]
+    fn test_scope_port_match() {
+        let single = port_scope(6, 443);
+        assert!(single.port_match(443));
+        assert!(!single.port_match(444));
+
+        let range = range_scope(17, 5000, 5010);
+        assert!(range.port_match(5000));
+        assert!(range.port_match(5010));
+        assert!(!range.port_match(4999));
+        assert!(!range.port_match(5011));
+
+        let any = Scope {
+            protocol: 1,
+            flag: None,
+            port: None,
+            port_range: None,
+        };
+        assert!(any.port_match(0));
+        assert!(any.port_match(u16::MAX));
+    }
+
+    #[test]
+    fn test_scope_flag_protocol() {
+        let mut scope = port_scope(17, 53);
+        scope.flag = Some(ScopeFlag::UdpOneWay);
+        assert!(scope.validate().is_ok());
+
+        scope.protocol = 6;
+        assert!(matches!(
+            scope.validate(),
+            Err(PolicyTypeError::ValidationError(_))
+        ));
+
+        scope.flag = Some(ScopeFlag::IcmpRequestReply);
+        assert!(scope.validate().is_err());
+        scope.protocol = 17;
+        assert!(scope.validate().is_err());
+        scope.protocol = 1;
+        assert!(scope.validate().is_ok());
+        scope.protocol = 58;
+        assert!(scope.validate().is_ok());
+
+        scope.flag = None;
+        scope.protocol = 6;
+        assert!(scope.validate().is_ok());
+
+        let mut bad = port_scope(6, 80);
+        bad.flag = Some(ScopeFlag::UdpOneWay);
+        assert!(policy_with_scopes(vec![bad]).validate().is_err());
+    }
+
+    fn policy_with_scopes(endpoints: Vec<Scope>) -> JoinPolicy {
+        JoinPolicy {
+            conditions: vec![],
+            flags: PFlags::default(),
+            provides: Some(vec![Service {
+                id: "web".to_string(),
+                endpoints,
+                kind: ServiceType::Regular,
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_validate_overlapping_scopes() {
+        let policy = policy_with_scopes(vec![port_scope(6, 80), range_scope(6, 79, 81)]);
+        assert!(matches!(
+            policy.validate(),
+            Err(PolicyTypeError::ValidationError(_))
+        ));
+
+        let policy =
+            policy_with_scopes(vec![range_scope(6, 1000, 2000), range_scope(6, 2000, 3000)]);
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_disjoint_scopes() {
+        let policy = policy_with_scopes(vec![
+            port_scope(6, 80),
+            range_scope(6, 81, 90),
+            port_scope(17, 80), // same port, different protocol
+        ]);
+        assert!(policy.validate().is_ok());
+    }
+}
@@ -9,7 +9,7 @@ mod topology;
 mod writer;
 
 pub use attr_exp::{AttrExp, AttrOp};
-pub use attribute::{AttrDomain, Attribute};
+pub use attribute::{AttrDomain, AttrKind, Attribute, attributes_in_domain, partition_by_domain};
 pub use error::{AttributeError, PolicyTypeError};
 pub use join::{JoinPolicy, PFlags, Scope, ScopeFlag, Service, ServiceType};
 pub use policy_bundle::{PolicyBundle, PolicyContainerBytes};
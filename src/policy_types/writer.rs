@@ -11,17 +11,94 @@ pub fn write_attributes(
         let mut ccond = conds.reborrow().get(j as u32);
         // foo:fee    (foo, eq, fee)
         // foo:       (foo, has, "")
+        // !foo:fee   (foo, ne, fee)
+        // !foo:      (foo, excludes, "")
+        // #tag       (domain.zpr.tag, eq, domain.tag)
+        // !#tag      (domain.zpr.tag, ne, domain.tag)
         ccond.set_key(&attr.zpl_key());
         let vals = attr.zpl_values();
 
-        if vals.is_empty() || vals[0].is_empty() || attr.is_multi_valued() {
-            ccond.set_op(v1::AttrOp::Has);
-        } else {
-            ccond.set_op(v1::AttrOp::Eq);
-        }
+        let has = vals.is_empty() || vals[0].is_empty() || attr.is_multi_valued();
+        let op = match (has, attr.negated) {
+            (true, false) => v1::AttrOp::Has,
+            (true, true) => v1::AttrOp::Excludes,
+            (false, false) => v1::AttrOp::Eq,
+            (false, true) => v1::AttrOp::Ne,
+        };
+        ccond.set_op(op);
         let mut cvals = ccond.init_value(vals.len() as u32);
         for (i, val) in vals.iter().enumerate() {
             cvals.set(i as u32, val);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_types::{AttrExp, AttrOp};
+
+    fn write_and_read(attrs: &[Attribute]) -> Vec<AttrExp> {
+        let mut msg = capnp::message::Builder::new_default();
+        let mut conds: capnp::struct_list::Builder<'_, v1::attr_expr::Owned> =
+            msg.initn_root(attrs.len() as u32);
+        write_attributes(attrs, &mut conds);
+        conds
+            .into_reader()
+            .iter()
+            .map(|r| AttrExp::try_from(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_write_attributes_default_ops() {
+        let attrs = vec![
+            Attribute::tuple("user.role")
+                .value("admin")
+                .build()
+                .unwrap(),
+            Attribute::tuple("user.role").build().unwrap(),
+        ];
+        let exps = write_and_read(&attrs);
+        assert_eq!(exps[0].op, AttrOp::Eq);
+        assert_eq!(exps[0].value, vec!["admin".to_string()]);
+        assert_eq!(exps[1].op, AttrOp::Has);
+    }
+
+    #[test]
+    fn test_write_attributes_negated_value() {
+        let attrs = vec![
+            Attribute::tuple("user.role")
+                .value("admin")
+                .negated(true)
+                .build()
+                .unwrap(),
+        ];
+        let exps = write_and_read(&attrs);
+        assert_eq!(exps[0].key, "user.role");
+        assert_eq!(exps[0].op, AttrOp::Ne);
+        assert_eq!(exps[0].value, vec!["admin".to_string()]);
+    }
+
+    #[test]
+    fn test_write_attributes_absent() {
+        let attrs = vec![
+            Attribute::tuple("user.clearance")
+                .negated(true)
+                .build()
+                .unwrap(),
+            Attribute::tag("endpoint.hardened")
+                .negated(true)
+                .build()
+                .unwrap(),
+        ];
+        let exps = write_and_read(&attrs);
+        assert_eq!(exps[0].op, AttrOp::Excludes);
+        assert!(exps[0].value.is_empty());
+        // A tag is written as an equality on the domain's tag key, so a negated
+        // tag is the matching inequality.
+        assert_eq!(exps[1].key, "endpoint.zpr.tag");
+        assert_eq!(exps[1].op, AttrOp::Ne);
+        assert_eq!(exps[1].value, vec!["endpoint.hardened".to_string()]);
+    }
+}
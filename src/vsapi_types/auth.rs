@@ -1,6 +1,8 @@
 use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::vsapi::v1;
+use crate::vsapi_types::VsapiTypeError;
 
 /// Blob passed with a ConnectRequest
 #[derive(Debug)]
@@ -9,15 +11,95 @@ pub enum AuthBlob {
     AC(AuthCodeBlob),
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct SelfSignedBlob {
-    pub alg: ChallengeAlg,
+    /// Always an algorithm in the vsapi schema, so the blob can be written.
+    alg: v1::ChallengeAlg,
     pub challenge: Vec<u8>,
     pub cn: String,
+    /// When the challenge was signed, in seconds since the UNIX epoch.
     pub timestamp: u64,
     pub signature: Vec<u8>,
 }
 
+impl Default for SelfSignedBlob {
+    fn default() -> Self {
+        SelfSignedBlob {
+            alg: v1::ChallengeAlg::RsaSha256Pkcs1v15,
+            challenge: Vec::new(),
+            cn: String::new(),
+            timestamp: 0,
+            signature: Vec::new(),
+        }
+    }
+}
+
+impl SelfSignedBlob {
+    /// Errors if `alg` is not in the vsapi schema, since the blob could not be
+    /// sent.
+    pub fn new(
+        alg: ChallengeAlg,
+        challenge: Vec<u8>,
+        cn: String,
+        timestamp: u64,
+        signature: Vec<u8>,
+    ) -> Result<Self, VsapiTypeError> {
+        Ok(SelfSignedBlob {
+            alg: alg.to_capnp()?,
+            challenge,
+            cn,
+            timestamp,
+            signature,
+        })
+    }
+
+    pub fn alg(&self) -> ChallengeAlg {
+        ChallengeAlg::from(self.alg)
+    }
+
+    /// Change the algorithm. Errors, leaving the blob as is, if `alg` is not in
+    /// the vsapi schema.
+    pub fn set_alg(&mut self, alg: ChallengeAlg) -> Result<(), VsapiTypeError> {
+        self.alg = alg.to_capnp()?;
+        Ok(())
+    }
+
+    pub(crate) fn capnp_alg(&self) -> v1::ChallengeAlg {
+        self.alg
+    }
+
+    /// Sign the challenge with the blob's algorithm and store the signature.
+    pub fn sign(&mut self, signer: &impl ChallengeSigner) -> Result<(), VsapiTypeError> {
+        self.signature = self.alg().sign(signer, &self.challenge)?;
+        Ok(())
+    }
+
+    /// Check the signature over the challenge with the blob's algorithm.
+    pub fn verify(&self, verifier: &impl ChallengeVerifier) -> Result<(), VsapiTypeError> {
+        self.alg()
+            .verify(verifier, &self.challenge, &self.signature)
+    }
+
+    /// Set the timestamp to the current time, in epoch seconds.
+    pub fn set_timestamp_now(&mut self) {
+        self.set_timestamp(SystemTime::now());
+    }
+
+    /// Set the timestamp from `time`, truncated to epoch seconds. Times before
+    /// the epoch are stored as 0.
+    pub fn set_timestamp(&mut self, time: SystemTime) {
+        self.timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+    }
+
+    /// The timestamp as a SystemTime, or None if it is too large to represent.
+    pub fn timestamp_as_system_time(&self) -> Option<SystemTime> {
+        UNIX_EPOCH.checked_add(Duration::from_secs(self.timestamp))
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthCodeBlob {
     pub asa_addr: IpAddr,
@@ -26,10 +108,109 @@ pub struct AuthCodeBlob {
     pub client_id: String,
 }
 
-#[derive(Debug, Default)]
+/// Signs a challenge with a given algorithm. This crate has no crypto
+/// dependencies, so the caller supplies the implementations.
+pub trait ChallengeSigner {
+    fn sign_rsa_sha256_pkcs1v15(&self, message: &[u8]) -> Vec<u8>;
+    fn sign_ed25519(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Checks a challenge signature with a given algorithm, see [ChallengeSigner].
+pub trait ChallengeVerifier {
+    fn verify_rsa_sha256_pkcs1v15(&self, message: &[u8], signature: &[u8]) -> bool;
+    fn verify_ed25519(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Signature algorithm used for a [SelfSignedBlob].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum ChallengeAlg {
     #[default]
     RsaSha256Pkcs1v15,
+    Ed25519,
+    /// An algorithm id we do not recognize.
+    Unknown(u16),
+}
+
+impl ChallengeAlg {
+    /// Numeric id of the algorithm. `RsaSha256Pkcs1v15` (0) is its ordinal in
+    /// vs.capnp. `Ed25519` is not in the schema yet, 1 is only reserved for it here.
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            ChallengeAlg::RsaSha256Pkcs1v15 => 0,
+            ChallengeAlg::Ed25519 => 1,
+            ChallengeAlg::Unknown(id) => *id,
+        }
+    }
+
+    /// Map a wire id to an algorithm. Unrecognized ids become [ChallengeAlg::Unknown].
+    pub fn from_u16(id: u16) -> Self {
+        match id {
+            0 => ChallengeAlg::RsaSha256Pkcs1v15,
+            1 => ChallengeAlg::Ed25519,
+            other => ChallengeAlg::Unknown(other),
+        }
+    }
+
+    /// The schema enum value for this algorithm. Only algorithms that are in
+    /// vs.capnp can be written; the rest are an error.
+    pub(crate) fn to_capnp(self) -> Result<v1::ChallengeAlg, VsapiTypeError> {
+        match self {
+            ChallengeAlg::RsaSha256Pkcs1v15 => Ok(v1::ChallengeAlg::RsaSha256Pkcs1v15),
+            other => Err(VsapiTypeError::ValidationError(format!(
+                "challenge algorithm {other:?} not in vsapi schema"
+            ))),
+        }
+    }
+
+    /// Sign `message` with this algorithm. Errors for an unknown algorithm.
+    pub fn sign(
+        self,
+        signer: &impl ChallengeSigner,
+        message: &[u8],
+    ) -> Result<Vec<u8>, VsapiTypeError> {
+        match self {
+            ChallengeAlg::RsaSha256Pkcs1v15 => Ok(signer.sign_rsa_sha256_pkcs1v15(message)),
+            ChallengeAlg::Ed25519 => Ok(signer.sign_ed25519(message)),
+            ChallengeAlg::Unknown(id) => Err(VsapiTypeError::ValidationError(format!(
+                "unknown challenge algorithm {id}"
+            ))),
+        }
+    }
+
+    /// Check `signature` over `message` with this algorithm. Errors for an
+    /// unknown algorithm or a bad signature.
+    pub fn verify(
+        self,
+        verifier: &impl ChallengeVerifier,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), VsapiTypeError> {
+        let valid = match self {
+            ChallengeAlg::RsaSha256Pkcs1v15 => {
+                verifier.verify_rsa_sha256_pkcs1v15(message, signature)
+            }
+            ChallengeAlg::Ed25519 => verifier.verify_ed25519(message, signature),
+            ChallengeAlg::Unknown(id) => {
+                return Err(VsapiTypeError::ValidationError(format!(
+                    "unknown challenge algorithm {id}"
+                )));
+            }
+        };
+        if !valid {
+            return Err(VsapiTypeError::ValidationError(format!(
+                "bad {self:?} challenge signature"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl From<v1::ChallengeAlg> for ChallengeAlg {
+    fn from(alg: v1::ChallengeAlg) -> Self {
+        match alg {
+            v1::ChallengeAlg::RsaSha256Pkcs1v15 => ChallengeAlg::RsaSha256Pkcs1v15,
+        }
+    }
 }
 
 impl TryFrom<v1::auth_blob::Reader<'_>> for AuthBlob {
@@ -55,9 +236,12 @@ impl TryFrom<v1::self_signed_blob::Reader<'_>> for SelfSignedBlob {
     type Error = crate::vsapi_types::VsapiTypeError;
 
     fn try_from(reader: v1::self_signed_blob::Reader<'_>) -> Result<Self, Self::Error> {
-        let alg = match reader.get_alg()? {
-            v1::ChallengeAlg::RsaSha256Pkcs1v15 => ChallengeAlg::RsaSha256Pkcs1v15,
-        };
+        let alg = reader.get_alg().map_err(|capnp::NotInSchema(id)| {
+            VsapiTypeError::DeserializationContext(format!(
+                "challenge algorithm {:?} not in vsapi schema",
+                ChallengeAlg::from_u16(id)
+            ))
+        })?;
         Ok(SelfSignedBlob {
             alg,
             challenge: reader.get_challenge()?.to_vec(),
@@ -81,3 +265,115 @@ impl TryFrom<v1::auth_code_blob::Reader<'_>> for AuthCodeBlob {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_alg_wire_id_roundtrip() {
+        for alg in [ChallengeAlg::RsaSha256Pkcs1v15, ChallengeAlg::Ed25519] {
+            assert_eq!(ChallengeAlg::from_u16(alg.as_u16()), alg);
+        }
+    }
+
+    #[test]
+    fn test_self_signed_blob_timestamp() {
+        let mut blob = SelfSignedBlob::default();
+        let t = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        blob.set_timestamp(t + Duration::from_millis(999));
+        assert_eq!(blob.timestamp, 1_700_000_000);
+        assert_eq!(blob.timestamp_as_system_time(), Some(t));
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        blob.set_timestamp_now();
+        let stamped = blob.timestamp_as_system_time().unwrap();
+        assert!(stamped >= before && stamped <= SystemTime::now());
+
+        blob.timestamp = u64::MAX;
+        assert_eq!(blob.timestamp_as_system_time(), None);
+    }
+
+    struct FakeCrypto;
+
+    impl ChallengeSigner for FakeCrypto {
+        fn sign_rsa_sha256_pkcs1v15(&self, message: &[u8]) -> Vec<u8> {
+            [b"rsa:", message].concat()
+        }
+        fn sign_ed25519(&self, message: &[u8]) -> Vec<u8> {
+            [b"ed:", message].concat()
+        }
+    }
+
+    impl ChallengeVerifier for FakeCrypto {
+        fn verify_rsa_sha256_pkcs1v15(&self, message: &[u8], signature: &[u8]) -> bool {
+            signature == self.sign_rsa_sha256_pkcs1v15(message)
+        }
+        fn verify_ed25519(&self, message: &[u8], signature: &[u8]) -> bool {
+            signature == self.sign_ed25519(message)
+        }
+    }
+
+    #[test]
+    fn test_challenge_alg_dispatch() {
+        let msg = b"challenge";
+        let rsa = ChallengeAlg::RsaSha256Pkcs1v15
+            .sign(&FakeCrypto, msg)
+            .unwrap();
+        let ed = ChallengeAlg::Ed25519.sign(&FakeCrypto, msg).unwrap();
+        assert_eq!(rsa, b"rsa:challenge");
+        assert_eq!(ed, b"ed:challenge");
+
+        assert!(
+            ChallengeAlg::RsaSha256Pkcs1v15
+                .verify(&FakeCrypto, msg, &rsa)
+                .is_ok()
+        );
+        assert!(ChallengeAlg::Ed25519.verify(&FakeCrypto, msg, &ed).is_ok());
+        assert!(
+            ChallengeAlg::Ed25519
+                .verify(&FakeCrypto, msg, &rsa)
+                .is_err()
+        );
+        assert!(ChallengeAlg::Unknown(9).sign(&FakeCrypto, msg).is_err());
+        assert!(
+            ChallengeAlg::Unknown(9)
+                .verify(&FakeCrypto, msg, &rsa)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_self_signed_blob_sign_and_verify() {
+        let mut blob = SelfSignedBlob::new(
+            ChallengeAlg::RsaSha256Pkcs1v15,
+            vec![1, 2, 3],
+            "alice".to_string(),
+            1_700_000_000,
+            Vec::new(),
+        )
+        .unwrap();
+        blob.sign(&FakeCrypto).unwrap();
+        assert!(blob.verify(&FakeCrypto).is_ok());
+        blob.challenge.push(4);
+        assert!(blob.verify(&FakeCrypto).is_err());
+    }
+
+    #[test]
+    fn test_self_signed_blob_rejects_alg_not_in_schema() {
+        for alg in [ChallengeAlg::Ed25519, ChallengeAlg::Unknown(7)] {
+            let res = SelfSignedBlob::new(alg, vec![1], "alice".to_string(), 0, vec![2]);
+            assert!(matches!(res, Err(VsapiTypeError::ValidationError(_))));
+        }
+
+        let mut blob = SelfSignedBlob::default();
+        assert!(blob.set_alg(ChallengeAlg::Ed25519).is_err());
+        assert_eq!(blob.alg(), ChallengeAlg::RsaSha256Pkcs1v15);
+    }
+
+    #[test]
+    fn test_challenge_alg_unknown_id() {
+        assert_eq!(ChallengeAlg::from_u16(999), ChallengeAlg::Unknown(999));
+        assert_eq!(ChallengeAlg::Unknown(999).as_u16(), 999);
+    }
+}
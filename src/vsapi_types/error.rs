@@ -19,11 +19,21 @@ pub enum VsapiTypeError {
     #[error("Deserialization error: {0:?}")]
     DeserializationError(&'static str),
 
+    /// Like DeserializationError, but with room for the offending value.
+    #[error("Deserialization error: {0}")]
+    DeserializationContext(String),
+
+    #[error("Validation error: {0}")]
+    ValidationError(String),
+
     #[error("IP address conversion error: {0}")]
     TryFromSliceError(#[from] std::array::TryFromSliceError),
 
     #[error("Addr Parse Error")]
     AddrParseError(#[from] AddrParseError),
+
+    #[error("URL parse error: {0}")]
+    UrlParseError(#[from] url::ParseError),
 }
 
 /// Error information.
@@ -45,7 +55,7 @@ impl std::fmt::Display for ApiResponseError {
     }
 }
 
-/// Denial code, match the codes in vs.capnp, except for Fail and UnknownStatusCode
+/// Denial code, match the codes in vs.capnp, except for Fail, UnknownStatusCode and Unknown
 #[derive(Clone, Debug)]
 pub enum ErrorCode {
     Internal,
@@ -60,6 +70,18 @@ pub enum ErrorCode {
     ParamError,
     UnknownStatusCode,
     Fail,
+    /// A code not in our copy of vs.capnp (eg, from a newer visa service).
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    /// Map a raw wire code to an ErrorCode, preserving codes we do not know.
+    pub fn from_u16(code: u16) -> Self {
+        match v1::ErrorCode::try_from(code) {
+            Ok(known) => known.into(),
+            Err(capnp::NotInSchema(unknown)) => ErrorCode::Unknown(unknown),
+        }
+    }
 }
 
 impl ApiResponseError {
@@ -86,7 +108,10 @@ impl TryFrom<v1::error::Reader<'_>> for ApiResponseError {
 
     // Altered to match functionality of former libnode2::vsconn::new_coded_error
     fn try_from(reader: v1::error::Reader<'_>) -> Result<Self, Self::Error> {
-        let code: ErrorCode = reader.get_code()?.into();
+        let code = match reader.get_code() {
+            Ok(known) => ErrorCode::from(known),
+            Err(capnp::NotInSchema(unknown)) => ErrorCode::Unknown(unknown),
+        };
         let message = reader.get_message()?.to_string()?;
         let retry_in = reader.get_retry_in();
 
@@ -115,6 +140,26 @@ impl Into<v1::ErrorCode> for ErrorCode {
             // These are not 1:1 mapped to IDL.
             ErrorCode::UnknownStatusCode => v1::ErrorCode::Internal,
             ErrorCode::Fail => v1::ErrorCode::Internal,
+            ErrorCode::Unknown(_) => v1::ErrorCode::Internal,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code_from_known_u16() {
+        let wire: v1::ErrorCode = ErrorCode::NotFound.into();
+        assert!(matches!(
+            ErrorCode::from_u16(wire as u16),
+            ErrorCode::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_error_code_from_unknown_u16() {
+        assert!(matches!(ErrorCode::from_u16(999), ErrorCode::Unknown(999)));
+    }
+}
@@ -0,0 +1,144 @@
+//! Fast hashing of five tuples for flow tables.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::net::IpAddr;
+
+use crate::vsapi_types::VsapiFiveTuple;
+
+/// Multiplier from FxHash (as used in rustc).
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+#[inline]
+fn fx_add(hash: u64, word: u64) -> u64 {
+    (hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED)
+}
+
+#[inline]
+fn fx_add_addr(hash: u64, addr: &IpAddr) -> u64 {
+    match addr {
+        IpAddr::V4(v4) => fx_add(hash, u32::from(*v4) as u64),
+        IpAddr::V6(v6) => {
+            let bits = v6.to_bits();
+            fx_add(fx_add(hash, (bits >> 64) as u64), bits as u64)
+        }
+    }
+}
+
+impl VsapiFiveTuple {
+    /// A fast, fixed (unkeyed) hash of the tuple for flow tables. This is cheaper
+    /// than the derived `Hash` with the default SipHash hasher, but it is NOT
+    /// resistant to hash flooding, so only use it for keys you trust.
+    ///
+    /// The L3 type is not hashed since it is implied by the addresses.
+    #[inline]
+    pub fn flow_hash(&self) -> u64 {
+        let hash = fx_add_addr(0, &self.source_addr);
+        let hash = fx_add_addr(hash, &self.dest_addr);
+        fx_add(
+            hash,
+            (self.source_port as u64) << 32
+                | (self.dest_port as u64) << 16
+                | self.l4_protocol as u64,
+        )
+    }
+}
+
+/// A [VsapiFiveTuple] that hashes with [VsapiFiveTuple::flow_hash]. Use as the key of
+/// a [FlowMap].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FlowKey(pub VsapiFiveTuple);
+
+impl Hash for FlowKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.flow_hash());
+    }
+}
+
+impl From<VsapiFiveTuple> for FlowKey {
+    fn from(tuple: VsapiFiveTuple) -> Self {
+        FlowKey(tuple)
+    }
+}
+
+/// Hasher that passes through the `flow_hash` written by [FlowKey]. Anything
+/// else written to it is mixed in FxHash style.
+#[derive(Default)]
+pub struct FlowHasher(u64);
+
+impl Hasher for FlowHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write_u64(&mut self, n: u64) {
+        self.0 = if self.0 == 0 { n } else { fx_add(self.0, n) };
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = fx_add(self.0, *b as u64);
+        }
+    }
+}
+
+/// HashMap keyed by five tuple using [VsapiFiveTuple::flow_hash].
+pub type FlowMap<V> = HashMap<FlowKey, V, BuildHasherDefault<FlowHasher>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_info::L3Type;
+    use crate::vsapi_types::vsapi_ip_number;
+    use std::collections::HashSet;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_flow_hash_collision_sanity() {
+        let mut hashes = HashSet::new();
+        let mut count = 0;
+        for i in 0..256u32 {
+            for port in 0..200u16 {
+                let v4 = VsapiFiveTuple::new(
+                    L3Type::Ipv4,
+                    IpAddr::V4(Ipv4Addr::from(0x0a00_0000 | i)),
+                    IpAddr::V4(Ipv4Addr::new(10, 1, 0, 1)),
+                    vsapi_ip_number::TCP,
+                    40000 + port,
+                    443,
+                );
+                let v6 = VsapiFiveTuple::new(
+                    L3Type::Ipv6,
+                    IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, i as u16)),
+                    IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 1, 0)),
+                    vsapi_ip_number::UDP,
+                    port,
+                    53,
+                );
+                hashes.insert(v4.flow_hash());
+                hashes.insert(v6.flow_hash());
+                hashes.insert(v4.reversed().flow_hash());
+                count += 3;
+            }
+        }
+        assert_eq!(hashes.len(), count);
+    }
+
+    #[test]
+    fn test_flow_map() {
+        let ft = VsapiFiveTuple::new(
+            L3Type::Ipv4,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            vsapi_ip_number::TCP,
+            40000,
+            443,
+        );
+        let mut map: FlowMap<u64> = FlowMap::default();
+        map.insert(ft.into(), 7);
+        assert_eq!(map.get(&FlowKey(ft)), Some(&7));
+        assert_eq!(map.get(&FlowKey(ft.reversed())), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Object safe serialization of top level vsapi messages.
+
+use crate::vsapi::v1;
+use crate::vsapi_types::{
+    ApiResponseError, ConnectRequest, Connection, Link, PacketDesc, ServiceDescriptor, SockAddr,
+    VSConnectRequest, Visa, VisaOp, VisaResponse, VsapiTypeError,
+};
+use crate::write_to::WriteTo;
+use capnp::message::{Reader, ReaderOptions};
+use capnp::serialize::OwnedSegments;
+use std::io::Cursor;
+
+/// Read a framed Cap'n Proto message from `bytes`. Take the root from the result
+/// and convert it, eg `Visa::try_from(read_root(bytes)?.get_root()?)`.
+pub fn read_root(bytes: &[u8]) -> Result<Reader<OwnedSegments>, VsapiTypeError> {
+    read_root_with_options(bytes, ReaderOptions::new())
+}
+
+/// Like [read_root] but with caller supplied reader options.
+pub fn read_root_with_options(
+    bytes: &[u8],
+    opts: ReaderOptions,
+) -> Result<Reader<OwnedSegments>, VsapiTypeError> {
+    Ok(capnp::serialize::read_message(Cursor::new(bytes), opts)?)
+}
+
+/// Write `value` as the root struct `O` of a new message and return the
+/// framed bytes, eg `write_root::<v1::visa::Owned, _>(&visa)`.
+pub(crate) fn write_root<O, T>(value: &T) -> Result<Vec<u8>, VsapiTypeError>
+where
+    O: capnp::traits::Owned,
+    T: for<'a> WriteTo<O::Builder<'a>> + ?Sized,
+{
+    let mut msg = capnp::message::Builder::new_default();
+    {
+        let mut root: O::Builder<'_> = msg.init_root();
+        value.write_to(&mut root);
+    }
+    message_to_bytes(&msg)
+}
+
+/// Like [write_root] but the root is a `List(O)` of `len` elements.
+pub(crate) fn write_list_root<O, T>(value: &T, len: usize) -> Result<Vec<u8>, VsapiTypeError>
+where
+    O: capnp::traits::OwnedStruct,
+    T: for<'a> WriteTo<capnp::struct_list::Builder<'a, O>> + ?Sized,
+{
+    let mut msg = capnp::message::Builder::new_default();
+    {
+        let mut root: capnp::struct_list::Builder<'_, O> = msg.initn_root(len as u32);
+        value.write_to(&mut root);
+    }
+    message_to_bytes(&msg)
+}
+
+fn message_to_bytes(
+    msg: &capnp::message::Builder<capnp::message::HeapAllocator>,
+) -> Result<Vec<u8>, VsapiTypeError> {
+    let mut buf = Vec::new();
+    capnp::serialize::write_message(&mut buf, msg)?;
+    Ok(buf)
+}
+
+/// Check that `decode` accepts `bytes`, rejects every truncation of them, and
+/// does not panic when any one byte is corrupted.
+#[cfg(test)]
+pub(crate) fn check_decode_rejects_damage<T>(
+    bytes: &[u8],
+    decode: impl Fn(&[u8]) -> Result<T, VsapiTypeError>,
+) {
+    assert!(decode(bytes).is_ok());
+    for len in 0..bytes.len() {
+        assert!(decode(&bytes[..len]).is_err(), "truncated to {len} bytes");
+    }
+    for i in 0..bytes.len() {
+        let mut corrupt = bytes.to_vec();
+        corrupt[i] ^= 0xff;
+        // Any result is fine, as long as decoding does not panic.
+        let _ = decode(&corrupt);
+    }
+}
+
+/// Serialize a message to framed Cap'n Proto bytes.
+///
+/// Unlike [WriteTo] this is not parameterized by the builder type, so it can be
+/// used as a trait object (eg, `Box<dyn CapnpSerialize>`) by code that handles
+/// many kinds of message.
+pub trait CapnpSerialize {
+    fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError>;
+}
+
+/// Implement [CapnpSerialize] for types that have a [WriteTo] impl for the
+/// builder of the named root struct in `v1`.
+macro_rules! impl_capnp_serialize {
+    ($($ty:ty => $root:ident),* $(,)?) => {
+        $(
+            impl CapnpSerialize for $ty {
+                fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+                    write_root::<v1::$root::Owned, _>(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_capnp_serialize! {
+    VisaOp => visa_op,
+    ConnectRequest => connect_request,
+    VSConnectRequest => v_s_connect_request,
+    Connection => connection,
+    ServiceDescriptor => service_descriptor,
+    ApiResponseError => error,
+    Link => link,
+}
+
+impl CapnpSerialize for PacketDesc {
+    fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        PacketDesc::to_capnp_bytes(self)
+    }
+}
+
+impl CapnpSerialize for Visa {
+    fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        Visa::to_capnp_bytes(self)
+    }
+}
+
+impl CapnpSerialize for VisaResponse {
+    fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        VisaResponse::to_capnp_bytes(self)
+    }
+}
+
+impl CapnpSerialize for SockAddr {
+    fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        SockAddr::to_capnp_bytes(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsapi_types::visa::make_visa;
+    use crate::vsapi_types::{AuthBlob, Claim, SelfSignedBlob, VisaPath};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_boxed_visa_to_capnp_bytes() {
+        let visa = make_visa();
+        let expected = visa.to_capnp_bytes().unwrap();
+
+        let msgs: Vec<Box<dyn CapnpSerialize>> = vec![
+            Box::new(visa),
+            Box::new(PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap()),
+        ];
+        let bytes = msgs[0].to_capnp_bytes().unwrap();
+        assert_eq!(bytes, expected);
+        let decoded = Visa::from_capnp_bytes(&bytes).unwrap();
+        assert_eq!(decoded.issuer_id, 42);
+        assert_eq!(
+            decoded.expires,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        assert!(!msgs[1].to_capnp_bytes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decoders_reject_damaged_bytes() {
+        check_decode_rejects_damage(
+            &make_visa().to_capnp_bytes().unwrap(),
+            Visa::from_capnp_bytes,
+        );
+
+        let path = VisaPath::new(vec![make_visa(), make_visa()]);
+        check_decode_rejects_damage(&path.to_capnp_bytes().unwrap(), VisaPath::from_capnp_bytes);
+
+        let sa = SockAddr {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            port: 5000,
+        };
+        check_decode_rejects_damage(&sa.to_capnp_bytes().unwrap(), SockAddr::from_capnp_bytes);
+        let addrs = [sa.clone(), sa];
+        check_decode_rejects_damage(
+            &SockAddr::list_to_capnp_bytes(&addrs).unwrap(),
+            SockAddr::list_from_capnp_bytes,
+        );
+
+        let resp = VisaResponse::Allowed(make_visa());
+        check_decode_rejects_damage(&resp.to_capnp_bytes().unwrap(), |b| {
+            VisaResponse::try_from(read_root(b)?.get_root::<v1::visa_response::Reader>()?)
+        });
+
+        let req = ConnectRequest {
+            blobs: vec![AuthBlob::SS(SelfSignedBlob::default())],
+            claims: vec![Claim::new("cn".to_string(), "alice".to_string())],
+            substrate_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dock_interface: 0,
+        };
+        check_decode_rejects_damage(&req.to_capnp_bytes().unwrap(), |b| {
+            ConnectRequest::try_from(read_root(b)?.get_root::<v1::connect_request::Reader>()?)
+        });
+
+        let pd = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        check_decode_rejects_damage(&pd.to_capnp_bytes().unwrap(), |b| {
+            PacketDesc::try_from(read_root(b)?.get_root::<v1::packet_desc::Reader>()?)
+        });
+    }
+
+    #[test]
+    fn test_read_root() {
+        let sa = SockAddr {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            port: 5000,
+        };
+        let bytes = sa.to_capnp_bytes().unwrap();
+        let decoded = SockAddr::try_from(
+            read_root(&bytes)
+                .unwrap()
+                .get_root::<v1::sock_addr::Reader>()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(decoded, sa);
+
+        let resp = VisaResponse::Allowed(make_visa());
+        let bytes = resp.to_capnp_bytes().unwrap();
+        let msg = read_root(&bytes).unwrap();
+        let root: v1::visa_response::Reader<'_> = msg.get_root().unwrap();
+        match VisaResponse::try_from(root).unwrap() {
+            VisaResponse::Allowed(visa) => assert_eq!(visa.issuer_id, 42),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        assert!(read_root(&[0, 0]).is_err());
+    }
+}
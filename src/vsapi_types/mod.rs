@@ -4,6 +4,8 @@
 
 mod auth;
 mod error;
+mod flow;
+mod message;
 mod packet;
 mod param;
 mod request;
@@ -16,25 +18,31 @@ mod vsnet;
 mod writer;
 
 // PUBLIC API EXPORTS
-pub use auth::{AuthBlob, AuthCodeBlob, ChallengeAlg, SelfSignedBlob};
+pub use auth::{
+    AuthBlob, AuthCodeBlob, ChallengeAlg, ChallengeSigner, ChallengeVerifier, SelfSignedBlob,
+};
 pub use error::{ApiResponseError, ErrorCode, VsapiTypeError};
+pub use flow::{FlowHasher, FlowKey, FlowMap};
+pub use message::{CapnpSerialize, read_root, read_root_with_options};
 pub use packet::{
     CommFlag, HasFiveTuple, PacketDesc, VsapiFiveTuple, VsapiIpProtocol, vsapi_ip_number,
 };
 pub use param::{Param, ParamValue, pname};
-pub use request::{Claim, ConnectRequest, ConnectType, VSConnectRequest, VisaRequest};
+pub use request::{
+    Claim, ConnectRequest, ConnectType, STANDARD_CLAIM_KEYS, VSConnectRequest, VisaRequest,
+};
 pub use response::{
     Connection, Denied, DenyCode, DisconnectNotice, DisconnectReason, VisaDecision, VisaResponse,
 };
 pub use services::{AuthServicesList, ServiceDescriptor};
 pub use topo::{Link, LinkRole};
-pub use util::ip::ip_addr_from_vec;
-pub use util::time::visa_expiration_timestamp_to_system_time;
+pub use util::ip::{canonicalize_ip, ip_addr_from_slice, ip_addr_from_vec};
+pub use util::time::{ExpirationUnit, visa_expiration_timestamp_to_system_time};
 pub use visa::{
     Constraints, DockPep, DockPepType, EndpointT, FwdPep, FwdPepStyle, IcmpPep, KeyFormat, KeySet,
-    TcpUdpPep, Visa, VisaOp, VisaType,
+    TcpUdpPep, Visa, VisaAuditRecord, VisaOp, VisaPath, VisaType,
 };
-pub use vsnet::SockAddr;
+pub use vsnet::{SockAddr, order_candidates};
 
 #[cfg(test)]
 mod tests {
@@ -74,6 +82,102 @@ mod tests {
         assert_eq!(list.services[0].service_id, "test-service-123");
     }
 
+    #[test]
+    fn test_auth_services_list_invalid_and_never_expires() {
+        let invalid = AuthServicesList::invalid();
+        assert!(!invalid.is_valid());
+        assert!(invalid.is_expired());
+        assert!(!AuthServicesList::default().is_valid());
+
+        let list = AuthServicesList::never_expires(vec![create_test_service_descriptor()]);
+        assert!(list.is_valid());
+        assert_eq!(list.expiration, None);
+        assert!(!AuthServicesList::never_expires(Vec::new()).is_valid());
+    }
+
+    #[test]
+    fn test_auth_services_list_sort() {
+        let a1 =
+            ServiceDescriptor::from_socket_addr("asa-a", "https", "10.0.0.2:8443".parse().unwrap());
+        let a2 =
+            ServiceDescriptor::from_socket_addr("asa-a", "https", "10.0.0.1:8443".parse().unwrap());
+        let b =
+            ServiceDescriptor::from_socket_addr("asa-b", "https", "10.0.0.0:8443".parse().unwrap());
+
+        let mut first = AuthServicesList::default();
+        first.update(None, vec![b.clone(), a1.clone(), a2.clone()]);
+        first.sort();
+        assert_eq!(first.services, vec![a2.clone(), a1.clone(), b.clone()]);
+
+        // A refresh with the same services in another order sorts the same way.
+        let mut second = AuthServicesList::default();
+        second.update(None, vec![a1, b, a2]);
+        second.sort();
+        assert_eq!(second.services, first.services);
+    }
+
+    #[test]
+    fn test_auth_services_list_merge_overlapping() {
+        let old_a =
+            ServiceDescriptor::from_socket_addr("asa-a", "https", "10.0.0.1:8443".parse().unwrap());
+        let new_a =
+            ServiceDescriptor::from_socket_addr("asa-a", "https", "10.0.0.9:8443".parse().unwrap());
+        let b =
+            ServiceDescriptor::from_socket_addr("asa-b", "https", "10.0.0.2:8443".parse().unwrap());
+        let c =
+            ServiceDescriptor::from_socket_addr("asa-c", "https", "10.0.0.3:8443".parse().unwrap());
+
+        let mut list = AuthServicesList::default();
+        list.update(None, vec![old_a, b.clone()]);
+        let mut other = AuthServicesList::default();
+        other.update(None, vec![c.clone(), new_a.clone()]);
+
+        list.merge(other);
+        assert_eq!(list.services, vec![new_a, b, c]);
+    }
+
+    #[test]
+    fn test_auth_services_list_merge_disjoint() {
+        let a =
+            ServiceDescriptor::from_socket_addr("asa-a", "https", "10.0.0.1:8443".parse().unwrap());
+        let b =
+            ServiceDescriptor::from_socket_addr("asa-b", "https", "10.0.0.2:8443".parse().unwrap());
+
+        let mut list = AuthServicesList::default();
+        list.update(None, vec![a.clone()]);
+        let mut other = AuthServicesList::default();
+        other.update(None, vec![b.clone()]);
+
+        list.merge(other);
+        assert_eq!(list.services, vec![a, b]);
+    }
+
+    #[test]
+    fn test_auth_services_list_merge_expiration() {
+        let earlier = SystemTime::now() + Duration::from_secs(60);
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        let with_exp = |exp| AuthServicesList {
+            expiration: exp,
+            services: Vec::new(),
+        };
+
+        let mut list = with_exp(Some(earlier));
+        list.merge(with_exp(Some(later)));
+        assert_eq!(list.expiration, Some(later));
+
+        let mut list = with_exp(Some(later));
+        list.merge(with_exp(Some(earlier)));
+        assert_eq!(list.expiration, Some(later));
+
+        let mut list = with_exp(Some(later));
+        list.merge(with_exp(None));
+        assert_eq!(list.expiration, None);
+
+        let mut list = with_exp(None);
+        list.merge(with_exp(Some(earlier)));
+        assert_eq!(list.expiration, None);
+    }
+
     #[test]
     fn test_auth_services_list_is_expired() {
         let mut list = AuthServicesList::default();
@@ -151,6 +255,23 @@ mod tests {
         assert!(socket_addr.is_none());
     }
 
+    #[test]
+    fn test_service_descriptor_parsed_uri() {
+        let mut descriptor = create_test_service_descriptor();
+        descriptor.service_uri = "https://auth.example.com:8443/auth/v2".to_string();
+        let uri = descriptor.parsed_uri().unwrap();
+        assert_eq!(uri.scheme(), "https");
+        assert_eq!(uri.host_str(), Some("auth.example.com"));
+        assert_eq!(uri.port(), Some(8443));
+        assert_eq!(uri.path(), "/auth/v2");
+
+        descriptor.service_uri = "not-a-valid-uri".to_string();
+        assert!(matches!(
+            descriptor.parsed_uri(),
+            Err(VsapiTypeError::UrlParseError(_))
+        ));
+    }
+
     #[test]
     fn test_service_descriptor_to_socket_addr_no_port() {
         let mut descriptor = create_test_service_descriptor();
@@ -179,4 +300,57 @@ mod tests {
         assert!(socket_addr.is_some());
         assert_eq!(socket_addr.unwrap().port(), 8080);
     }
+
+    #[test]
+    fn test_service_descriptor_socket_addr_by_host_numeric() {
+        let mut descriptor = create_test_service_descriptor();
+        descriptor.service_uri = "https://10.1.2.3:8443/auth".to_string();
+
+        let addrs = descriptor.get_socket_addr_by_host().unwrap();
+        let expected: std::net::SocketAddr = "10.1.2.3:8443".parse().unwrap();
+        assert_eq!(addrs, vec![expected]);
+    }
+
+    #[test]
+    fn test_service_descriptor_socket_addr_by_host_invalid_uri() {
+        let mut descriptor = create_test_service_descriptor();
+        descriptor.service_uri = "not-a-valid-uri".to_string();
+
+        assert!(descriptor.get_socket_addr_by_host().is_err());
+    }
+
+    #[test]
+    fn test_service_descriptor_from_socket_addr_round_trip() {
+        for sa in ["192.168.1.100:8443", "[2001:db8::1]:9443"] {
+            let sa: std::net::SocketAddr = sa.parse().unwrap();
+            let descriptor = ServiceDescriptor::from_socket_addr("asa-1", "https", sa);
+            assert_eq!(descriptor.service_id, "asa-1");
+            assert_eq!(descriptor.zpr_addr, sa.ip());
+            assert_eq!(descriptor.get_socket_addr(), Some(sa));
+        }
+    }
+
+    #[cfg(feature = "policy")]
+    #[test]
+    fn test_service_type_to_vsapi_service_t() {
+        use crate::policy_types::ServiceType;
+        use crate::vsapi::v1;
+
+        let st = v1::ServiceT::try_from(&ServiceType::Authentication).unwrap();
+        assert_eq!(st, v1::ServiceT::ActorAuthentication);
+        assert_eq!(ServiceType::from(st), ServiceType::Authentication);
+
+        for st in [
+            ServiceType::Undefined,
+            ServiceType::Trusted("my-api".to_string()),
+            ServiceType::Visa,
+            ServiceType::Regular,
+            ServiceType::BuiltIn,
+        ] {
+            assert!(matches!(
+                v1::ServiceT::try_from(&st),
+                Err(VsapiTypeError::ValidationError(_))
+            ));
+        }
+    }
 }
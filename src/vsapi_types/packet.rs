@@ -1,9 +1,13 @@
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use crate::packet_info::L3Type;
+use crate::addrs;
+use crate::packet_info::{L3Type, L3TypeDeriveable};
 use crate::vsapi::v1;
-use crate::vsapi_types::VsapiTypeError;
+use crate::vsapi_types::message::write_root;
+use crate::vsapi_types::util::ip::ip_addr_from_slice;
+use crate::vsapi_types::{SockAddr, VsapiTypeError};
 
 pub trait HasFiveTuple {
     fn get_five_tuple(&self) -> VsapiFiveTuple;
@@ -20,16 +24,53 @@ pub struct PacketDesc {
 /// Special hint that is passed with a [PacketDesc].
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CommFlag {
-    /// TODO: document this
+    /// The flow is a conversation: the visa also covers the return traffic
+    /// (the reversed five tuple), so no separate visa is requested for it.
     BiDirectional,
-    /// TODO: document this
+    /// The flow is one way only: return traffic is not covered and needs
+    /// its own visa.
     UniDirectional,
     /// Is-a re-request, includes previous visa id.
     ReRequest(u64),
 }
 
+impl CommFlag {
+    pub fn is_rerequest(&self) -> bool {
+        matches!(self, CommFlag::ReRequest(_))
+    }
+
+    /// The id of the visa being re-requested, if this is a re-request.
+    pub fn prior_visa_id(&self) -> Option<u64> {
+        match self {
+            CommFlag::ReRequest(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn is_bidirectional(&self) -> bool {
+        *self == CommFlag::BiDirectional
+    }
+
+    /// True if a visa granted for this request should also admit the return
+    /// flow. A re-request does not say which direction the original request
+    /// had, so it is false here; use [CommFlag::implies_return_flow_with_prior]
+    /// when the prior flag is known.
+    pub fn implies_return_flow(&self) -> bool {
+        self.is_bidirectional()
+    }
+
+    /// Like [CommFlag::implies_return_flow] but a re-request inherits the answer
+    /// from `prior`, the flag of the request that produced the original visa.
+    pub fn implies_return_flow_with_prior(&self, prior: CommFlag) -> bool {
+        match self {
+            CommFlag::ReRequest(_) => prior.implies_return_flow(),
+            _ => self.implies_return_flow(),
+        }
+    }
+}
+
 /// FiveTuple representation
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct VsapiFiveTuple {
     pub source_addr: IpAddr,
     pub dest_addr: IpAddr,
@@ -56,6 +97,82 @@ pub mod vsapi_ip_number {
     pub const AH: VsapiIpProtocol = 51;
     pub const IPV6_ICMP: VsapiIpProtocol = 58;
     pub const IPV6_OPTS: VsapiIpProtocol = 60;
+
+    /// Every protocol above, with its name.
+    pub const ALL: &[(VsapiIpProtocol, &str)] = &[
+        (HOPOPT, "HOPOPT"),
+        (ICMP, "ICMP"),
+        (IPINIP, "IPINIP"),
+        (TCP, "TCP"),
+        (UDP, "UDP"),
+        (IPV6_ROUTE, "IPV6_ROUTE"),
+        (IPV6_FRAG, "IPV6_FRAG"),
+        (AH, "AH"),
+        (IPV6_ICMP, "IPV6_ICMP"),
+        (IPV6_OPTS, "IPV6_OPTS"),
+    ];
+
+    /// The name of `p` from [ALL], eg "TCP".
+    pub fn name(p: VsapiIpProtocol) -> Option<&'static str> {
+        ALL.iter().find(|(n, _)| *n == p).map(|(_, name)| *name)
+    }
+
+    /// True for [ICMP] and [IPV6_ICMP], whose "ports" are an ICMP type and code.
+    pub const fn is_icmp(p: VsapiIpProtocol) -> bool {
+        p == ICMP || p == IPV6_ICMP
+    }
+
+    /// Pack an ICMP type and code into one port-sized field as `(type << 8) | code`.
+    /// This is the wire encoding for both ICMP dock peps and ICMP packet descs.
+    pub const fn pack_icmp_type_code(icmp_type: u8, icmp_code: u8) -> u16 {
+        ((icmp_type as u16) << 8) | (icmp_code as u16)
+    }
+
+    /// Inverse of [pack_icmp_type_code], returns `(type, code)`.
+    pub const fn unpack_icmp_type_code(type_code: u16) -> (u8, u8) {
+        ((type_code >> 8) as u8, type_code as u8)
+    }
+
+    /// True if `p` is one of the protocols in [ALL].
+    pub fn is_known(p: VsapiIpProtocol) -> bool {
+        ALL.iter().any(|(n, _)| *n == p)
+    }
+
+    /// Parse a protocol given either as a name from [ALL] (case-insensitive, eg
+    /// "tcp") or as a decimal number (eg "6").
+    pub fn parse_proto(s: &str) -> Option<VsapiIpProtocol> {
+        let s = s.trim();
+        if let Ok(n) = s.parse::<VsapiIpProtocol>() {
+            return Some(n);
+        }
+        ALL.iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(n, _)| *n)
+    }
+}
+
+impl L3Type {
+    /// The ICMP protocol number for this L3 type: [vsapi_ip_number::IPV6_ICMP] for
+    /// IPv6, otherwise [vsapi_ip_number::ICMP].
+    pub fn icmp_protocol(&self) -> VsapiIpProtocol {
+        if *self == L3Type::Ipv6 {
+            vsapi_ip_number::IPV6_ICMP
+        } else {
+            vsapi_ip_number::ICMP
+        }
+    }
+}
+
+impl L3TypeDeriveable for VsapiFiveTuple {
+    fn l3_type(&self) -> L3Type {
+        self.l3_type
+    }
+}
+
+impl L3TypeDeriveable for PacketDesc {
+    fn l3_type(&self) -> L3Type {
+        self.five_tuple.l3_type
+    }
 }
 
 impl HasFiveTuple for VsapiFiveTuple {
@@ -65,7 +182,9 @@ impl HasFiveTuple for VsapiFiveTuple {
 }
 
 impl VsapiFiveTuple {
-    pub fn new(
+    /// Usable in const context, eg to build a well-known flow such as
+    /// [visa_service_five_tuple](crate::addrs::visa_service_five_tuple).
+    pub const fn new(
         l3_type: L3Type,
         source_addr: IpAddr,
         dest_addr: IpAddr,
@@ -82,9 +201,170 @@ impl VsapiFiveTuple {
             dest_port,
         }
     }
+
+    /// Build a five tuple from a source and dest socket address. Errors if one is
+    /// IPv4 and the other IPv6.
+    pub fn from_sock_addrs(
+        src: &SockAddr,
+        dst: &SockAddr,
+        proto: VsapiIpProtocol,
+    ) -> Result<Self, VsapiTypeError> {
+        let l3 = L3Type::new_from_addr(&src.addr);
+        if L3Type::new_from_addr(&dst.addr) != l3 {
+            return Err(VsapiTypeError::ValidationError(format!(
+                "address families differ: {} -> {}",
+                src.addr, dst.addr
+            )));
+        }
+        Ok(Self::new(l3, src.addr, dst.addr, proto, src.port, dst.port))
+    }
+
+    /// Build a five tuple from the raw address octets of an IP header, as parsed
+    /// by a packet handler. Each address must be 4 or 16 bytes and match `l3`.
+    pub fn from_ip_header(
+        l3: L3Type,
+        proto: VsapiIpProtocol,
+        src_octets: &[u8],
+        dst_octets: &[u8],
+        src_port: u16,
+        dst_port: u16,
+    ) -> Result<Self, VsapiTypeError> {
+        let source_addr = ip_addr_from_slice(src_octets)?;
+        let dest_addr = ip_addr_from_slice(dst_octets)?;
+        for addr in [&source_addr, &dest_addr] {
+            if L3Type::new_from_addr(addr) != l3 {
+                return Err(VsapiTypeError::DeserializationContext(format!(
+                    "address {addr} does not match L3 type {l3}"
+                )));
+            }
+        }
+        Ok(Self::new(
+            l3,
+            source_addr,
+            dest_addr,
+            proto,
+            src_port,
+            dst_port,
+        ))
+    }
+
+    /// The ICMP type and code held in the source and dest port fields. Errors
+    /// if either does not fit in a byte.
+    pub fn icmp_type_code(&self) -> Result<(u8, u8), VsapiTypeError> {
+        let icmp_type = u8::try_from(self.source_port).map_err(|_| {
+            VsapiTypeError::ValidationError(format!("ICMP type out of range: {}", self.source_port))
+        })?;
+        let icmp_code = u8::try_from(self.dest_port).map_err(|_| {
+            VsapiTypeError::ValidationError(format!("ICMP code out of range: {}", self.dest_port))
+        })?;
+        Ok((icmp_type, icmp_code))
+    }
+
+    /// Get the source address.
+    pub fn src_addr(&self) -> IpAddr {
+        self.source_addr
+    }
+    /// Get the destination address.
+    pub fn dst_addr(&self) -> IpAddr {
+        self.dest_addr
+    }
+    /// Get the source port (or icmp TYPE)
+    pub fn src_port(&self) -> u16 {
+        self.source_port
+    }
+    /// Get the destination port (or icmp CODE)
+    pub fn dst_port(&self) -> u16 {
+        self.dest_port
+    }
+    /// Get the L4 protocol number.
+    pub fn protocol(&self) -> VsapiIpProtocol {
+        self.l4_protocol
+    }
+    /// Get the L3 type.
+    pub fn l3_type(&self) -> L3Type {
+        self.l3_type
+    }
+
+    /// True if this is a flow to the visa service (TCP to
+    /// [VISA_SERVICE_ADDR](crate::addrs::VISA_SERVICE_ADDR) on
+    /// [VISA_SERVICE_PORT](crate::addrs::VISA_SERVICE_PORT)).
+    pub fn is_visa_service_flow(&self) -> bool {
+        self.dest_addr == addrs::VISA_SERVICE_ADDR
+            && self.l4_protocol == addrs::VISA_SERVICE_PROTO
+            && self.dest_port == addrs::VISA_SERVICE_PORT
+    }
+
+    /// True if both addresses are of the family given by the L3 type.
+    pub fn is_consistent(&self) -> bool {
+        L3Type::new_from_addr(&self.source_addr) == self.l3_type
+            && L3Type::new_from_addr(&self.dest_addr) == self.l3_type
+    }
+
+    /// The five tuple for traffic flowing the other way: source and destination
+    /// addresses and ports are swapped.
+    pub fn reversed(&self) -> Self {
+        Self {
+            source_addr: self.dest_addr,
+            dest_addr: self.source_addr,
+            l3_type: self.l3_type,
+            l4_protocol: self.l4_protocol,
+            source_port: self.dest_port,
+            dest_port: self.source_port,
+        }
+    }
+
+    /// A flow key that is the same for both directions of a flow.  The (addr, port)
+    /// endpoints are ordered so that the lesser one comes first, so a tuple and its
+    /// [reversed](Self::reversed) form produce the same key.
+    pub fn canonical_key(&self) -> (IpAddr, IpAddr, u16, u16, VsapiIpProtocol) {
+        let src = (self.source_addr, self.source_port);
+        let dst = (self.dest_addr, self.dest_port);
+        let (lo, hi) = if src <= dst { (src, dst) } else { (dst, src) };
+        (lo.0, hi.0, lo.1, hi.1, self.l4_protocol)
+    }
+
+    /// The `(source, dest)` socket addresses for a TCP or UDP tuple. Returns None
+    /// for any other protocol (eg, for ICMP the "ports" are really type and code).
+    pub fn socket_addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+        match self.l4_protocol {
+            vsapi_ip_number::TCP | vsapi_ip_number::UDP => Some((
+                SocketAddr::new(self.source_addr, self.source_port),
+                SocketAddr::new(self.dest_addr, self.dest_port),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Render the tuple as `src_addr:port -> dst_addr:port proto N` into `out`.
+    /// Lets a caller reuse a buffer rather than allocating with `format!`.
+    pub fn write_display(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(
+            out,
+            "{} -> {} proto {}",
+            SocketAddr::new(self.source_addr, self.source_port),
+            SocketAddr::new(self.dest_addr, self.dest_port),
+            self.l4_protocol
+        )
+    }
+}
+
+impl fmt::Display for VsapiFiveTuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_display(f)
+    }
 }
 
 impl PacketDesc {
+    /// Serialize into a framed Cap'n Proto `PacketDesc` message. Errors for an
+    /// ICMP descriptor whose type or code does not fit in a byte, since the two
+    /// are packed into one port field on the wire.
+    pub fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        if vsapi_ip_number::is_icmp(self.protocol()) {
+            self.five_tuple.icmp_type_code()?;
+        }
+        write_root::<v1::packet_desc::Owned, _>(self)
+    }
+
     pub fn new_tcp(
         source_addr: &str,
         dest_addr: &str,
@@ -137,11 +417,7 @@ impl PacketDesc {
                 L3Type::new_from_addr(&saddr),
                 saddr,
                 dest_addr.parse()?,
-                if saddr.is_ipv4() {
-                    vsapi_ip_number::ICMP
-                } else {
-                    vsapi_ip_number::IPV6_ICMP
-                },
+                L3Type::new_from_addr(&saddr).icmp_protocol(),
                 icmp_type as u16,
                 icmp_code as u16,
             ),
@@ -198,11 +474,7 @@ impl PacketDesc {
                 L3Type::new_from_addr(&source_addr),
                 source_addr,
                 dest_addr,
-                if source_addr.is_ipv4() {
-                    vsapi_ip_number::ICMP
-                } else {
-                    vsapi_ip_number::IPV6_ICMP
-                },
+                L3Type::new_from_addr(&source_addr).icmp_protocol(),
                 icmp_type as u16,
                 icmp_code as u16,
             ),
@@ -210,6 +482,13 @@ impl PacketDesc {
         })
     }
 
+    /// Replace the comm flag picked by the constructor, eg to make a one-way UDP
+    /// descriptor: `PacketDesc::new_udp(..)?.with_comm_flag(CommFlag::UniDirectional)`.
+    pub fn with_comm_flag(mut self, flag: CommFlag) -> Self {
+        self.comm_flags = flag;
+        self
+    }
+
     pub fn is_tcpudp(&self) -> bool {
         self.five_tuple.l4_protocol == vsapi_ip_number::TCP
             || self.five_tuple.l4_protocol == vsapi_ip_number::UDP
@@ -237,10 +516,19 @@ impl PacketDesc {
     }
 }
 
+/// The protocols a visa can be requested for.
+const PACKET_DESC_PROTOCOLS: &[VsapiIpProtocol] = &[
+    vsapi_ip_number::TCP,
+    vsapi_ip_number::UDP,
+    vsapi_ip_number::ICMP,
+    vsapi_ip_number::IPV6_ICMP,
+];
+
 impl TryFrom<v1::packet_desc::Reader<'_>> for PacketDesc {
     type Error = VsapiTypeError;
 
-    /// Returns error if fields are not set or if IP addres are badly formatted
+    /// Returns error if fields are not set, if IP addres are badly formatted or if
+    /// the protocol is not one we can issue a visa for.
     fn try_from(reader: v1::packet_desc::Reader<'_>) -> Result<Self, Self::Error> {
         let source_ip = reader.get_source_addr()?;
         let source = match source_ip.which()? {
@@ -264,25 +552,421 @@ impl TryFrom<v1::packet_desc::Reader<'_>> for PacketDesc {
                 IpAddr::V6(Ipv6Addr::from(octets))
             }
         };
-        let source_port = reader.get_source_port();
-        let dest_port = reader.get_dest_port();
         let protocol = reader.get_protocol();
+        let (source_port, dest_port) = if vsapi_ip_number::is_icmp(protocol) {
+            // See the PacketDesc writer: type and code are packed in source_port.
+            let (icmp_type, icmp_code) =
+                vsapi_ip_number::unpack_icmp_type_code(reader.get_source_port());
+            (icmp_type as u16, icmp_code as u16)
+        } else {
+            (reader.get_source_port(), reader.get_dest_port())
+        };
+        if !PACKET_DESC_PROTOCOLS.contains(&protocol) {
+            // Includes 0 (HOPOPT), which is what an unset protocol reads as.
+            return Err(VsapiTypeError::DeserializationContext(format!(
+                "unsupported packet_desc protocol: {protocol}"
+            )));
+        }
         let comm_flags = match reader.get_comm_type()? {
             v1::CommType::Bidirectional => CommFlag::BiDirectional,
             v1::CommType::Unidirectional => CommFlag::UniDirectional,
             v1::CommType::Rerequest => CommFlag::ReRequest(0), // TODO
         };
 
+        let five_tuple = VsapiFiveTuple::new(
+            L3Type::new_from_addr(&source),
+            source,
+            dest,
+            protocol,
+            source_port,
+            dest_port,
+        );
+        if !five_tuple.is_consistent() {
+            return Err(VsapiTypeError::DeserializationContext(format!(
+                "packet_desc address families differ: {source} -> {dest}"
+            )));
+        }
+
         Ok(PacketDesc {
-            five_tuple: VsapiFiveTuple::new(
-                L3Type::new_from_addr(&source),
-                source,
-                dest,
-                protocol,
-                source_port,
-                dest_port,
-            ),
+            five_tuple,
             comm_flags,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_tuple() -> VsapiFiveTuple {
+        VsapiFiveTuple::new(
+            L3Type::Ipv4,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            vsapi_ip_number::TCP,
+            40000,
+            443,
+        )
+    }
+
+    #[test]
+    fn test_five_tuple_accessors() {
+        let ft = tcp_tuple();
+        assert_eq!(ft.src_addr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(ft.dst_addr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(ft.src_port(), 40000);
+        assert_eq!(ft.dst_port(), 443);
+        assert_eq!(ft.protocol(), vsapi_ip_number::TCP);
+        assert_eq!(ft.l3_type(), L3Type::Ipv4);
+    }
+
+    #[test]
+    fn test_vsapi_ip_number_all() {
+        assert!(vsapi_ip_number::ALL.contains(&(6, "TCP")));
+        assert!(vsapi_ip_number::ALL.contains(&(17, "UDP")));
+        assert!(vsapi_ip_number::is_known(vsapi_ip_number::IPV6_ICMP));
+        assert!(!vsapi_ip_number::is_known(132)); // SCTP
+        assert_eq!(vsapi_ip_number::name(vsapi_ip_number::UDP), Some("UDP"));
+        assert_eq!(vsapi_ip_number::name(132), None);
+    }
+
+    fn packet_desc_round_trip(pd: &PacketDesc) -> Result<PacketDesc, VsapiTypeError> {
+        use crate::write_to::WriteTo;
+
+        let mut msg = capnp::message::Builder::new_default();
+        let mut root: v1::packet_desc::Builder<'_> = msg.init_root();
+        pd.write_to(&mut root);
+        PacketDesc::try_from(root.into_reader())
+    }
+
+    #[test]
+    fn test_packet_desc_icmp_round_trip() {
+        let pd = PacketDesc::new_icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap();
+
+        let mut msg = capnp::message::Builder::new_default();
+        let mut root: v1::packet_desc::Builder<'_> = msg.init_root();
+        crate::write_to::WriteTo::write_to(&pd, &mut root);
+        assert_eq!(root.reborrow_as_reader().get_source_port(), 0x0800);
+        assert_eq!(root.reborrow_as_reader().get_dest_port(), 0);
+
+        let read = packet_desc_round_trip(&pd).unwrap();
+        assert_eq!(read.protocol(), vsapi_ip_number::ICMP);
+        assert_eq!(read.source_port(), 8);
+        assert_eq!(read.dest_port(), 0);
+        assert_eq!(read.five_tuple, pd.five_tuple);
+
+        let pd = PacketDesc::new_icmp("fd00::1", "fd00::2", 1, 4).unwrap();
+        assert_eq!(
+            packet_desc_round_trip(&pd).unwrap().five_tuple,
+            pd.five_tuple
+        );
+    }
+
+    #[test]
+    fn test_packet_desc_icmp_out_of_range() {
+        let mut pd = PacketDesc::new_icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap();
+        assert!(pd.to_capnp_bytes().is_ok());
+
+        pd.five_tuple.source_port = 0x0108;
+        assert!(matches!(
+            pd.to_capnp_bytes(),
+            Err(VsapiTypeError::ValidationError(_))
+        ));
+        pd.five_tuple.source_port = 8;
+        pd.five_tuple.dest_port = 256;
+        assert!(pd.to_capnp_bytes().is_err());
+
+        // Ports above 255 are fine for TCP.
+        let pd = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        assert!(pd.to_capnp_bytes().is_ok());
+    }
+
+    #[test]
+    fn test_icmp_type_code_packing() {
+        use vsapi_ip_number::{is_icmp, pack_icmp_type_code, unpack_icmp_type_code};
+
+        assert_eq!(pack_icmp_type_code(8, 0), 0x0800);
+        assert_eq!(pack_icmp_type_code(3, 13), 0x030d);
+        assert_eq!(unpack_icmp_type_code(0x030d), (3, 13));
+        assert!(is_icmp(vsapi_ip_number::ICMP));
+        assert!(is_icmp(vsapi_ip_number::IPV6_ICMP));
+        assert!(!is_icmp(vsapi_ip_number::TCP));
+    }
+
+    #[test]
+    fn test_packet_desc_reader_protocol() {
+        let pd = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        let read = packet_desc_round_trip(&pd).unwrap();
+        assert_eq!(read.protocol(), vsapi_ip_number::TCP);
+
+        let mut unset = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        unset.five_tuple.l4_protocol = vsapi_ip_number::HOPOPT;
+        assert!(matches!(
+            packet_desc_round_trip(&unset),
+            Err(VsapiTypeError::DeserializationContext(_))
+        ));
+
+        unset.five_tuple.l4_protocol = vsapi_ip_number::AH;
+        assert!(packet_desc_round_trip(&unset).is_err());
+    }
+
+    #[test]
+    fn test_parse_proto() {
+        use vsapi_ip_number::parse_proto;
+        assert_eq!(parse_proto("tcp"), Some(vsapi_ip_number::TCP));
+        assert_eq!(parse_proto("TCP"), Some(vsapi_ip_number::TCP));
+        assert_eq!(parse_proto("6"), Some(vsapi_ip_number::TCP));
+        assert_eq!(parse_proto("17"), Some(vsapi_ip_number::UDP));
+        assert_eq!(parse_proto("ipv6_icmp"), Some(vsapi_ip_number::IPV6_ICMP));
+        assert_eq!(parse_proto("garbage"), None);
+        assert_eq!(parse_proto("256"), None);
+        assert_eq!(parse_proto(""), None);
+    }
+
+    #[test]
+    fn test_comm_flag_accessors() {
+        let bi = CommFlag::BiDirectional;
+        assert!(bi.is_bidirectional());
+        assert!(!bi.is_rerequest());
+        assert_eq!(bi.prior_visa_id(), None);
+
+        let uni = CommFlag::UniDirectional;
+        assert!(!uni.is_bidirectional());
+        assert!(!uni.is_rerequest());
+        assert_eq!(uni.prior_visa_id(), None);
+
+        let re = CommFlag::ReRequest(42);
+        assert!(!re.is_bidirectional());
+        assert!(re.is_rerequest());
+        assert_eq!(re.prior_visa_id(), Some(42));
+    }
+
+    #[test]
+    fn test_packet_desc_with_comm_flag() {
+        let pd = PacketDesc::new_udp("10.0.0.1", "10.0.0.2", 5000, 514).unwrap();
+        assert_eq!(pd.comm_flags, CommFlag::BiDirectional);
+        let pd = pd.with_comm_flag(CommFlag::UniDirectional);
+        assert_eq!(pd.comm_flags, CommFlag::UniDirectional);
+        assert_eq!(pd.protocol(), vsapi_ip_number::UDP);
+        assert_eq!(pd.dest_port(), 514);
+
+        let read = packet_desc_round_trip(&pd).unwrap();
+        assert_eq!(read.comm_flags, CommFlag::UniDirectional);
+    }
+
+    #[test]
+    fn test_five_tuple_from_sock_addrs() {
+        let src = SockAddr {
+            addr: "fd5a:5052::1".parse().unwrap(),
+            port: 40000,
+        };
+        let dst = SockAddr {
+            addr: "fd5a:5052::2".parse().unwrap(),
+            port: 443,
+        };
+        let ft = VsapiFiveTuple::from_sock_addrs(&src, &dst, vsapi_ip_number::TCP).unwrap();
+        assert_eq!(ft.l3_type, L3Type::Ipv6);
+        assert_eq!(ft.source_addr, src.addr);
+        assert_eq!(ft.dest_addr, dst.addr);
+        assert_eq!(ft.source_port, 40000);
+        assert_eq!(ft.dest_port, 443);
+        assert_eq!(ft.l4_protocol, vsapi_ip_number::TCP);
+
+        let v4 = SockAddr {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            port: 443,
+        };
+        assert!(matches!(
+            VsapiFiveTuple::from_sock_addrs(&src, &v4, vsapi_ip_number::TCP),
+            Err(VsapiTypeError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_five_tuple_is_visa_service_flow() {
+        let local: IpAddr = "fd5a:5052::99".parse().unwrap();
+        let vs_flow = addrs::visa_service_five_tuple(local, 40000).unwrap();
+        assert!(vs_flow.is_visa_service_flow());
+
+        let data = VsapiFiveTuple::new(
+            L3Type::Ipv6,
+            local,
+            "fd5a:5052::2".parse().unwrap(),
+            vsapi_ip_number::TCP,
+            40000,
+            addrs::VISA_SERVICE_PORT,
+        );
+        assert!(!data.is_visa_service_flow());
+        assert!(!vs_flow.reversed().is_visa_service_flow());
+    }
+
+    #[test]
+    fn test_five_tuple_is_consistent() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let v6: IpAddr = "fd00::1".parse().unwrap();
+        let ft = |l3, src, dst| VsapiFiveTuple::new(l3, src, dst, vsapi_ip_number::TCP, 1, 2);
+
+        assert!(ft(L3Type::Ipv4, v4, v4).is_consistent());
+        assert!(ft(L3Type::Ipv6, v6, v6).is_consistent());
+        assert!(!ft(L3Type::Ipv6, v4, v4).is_consistent());
+        assert!(!ft(L3Type::Ipv4, v4, v6).is_consistent());
+        assert!(!ft(L3Type::Ipv6, v4, v6).is_consistent());
+
+        let mut mixed = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        mixed.five_tuple.dest_addr = v6;
+        assert!(matches!(
+            packet_desc_round_trip(&mixed),
+            Err(VsapiTypeError::DeserializationContext(_))
+        ));
+    }
+
+    #[test]
+    fn test_l3_type_deriveable() {
+        fn derive(x: &impl L3TypeDeriveable) -> L3Type {
+            x.l3_type()
+        }
+
+        let v4 = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        assert_eq!(derive(&v4), L3Type::Ipv4);
+        assert_eq!(derive(&v4.five_tuple), L3Type::Ipv4);
+
+        let v6 = PacketDesc::new_udp("fd00::1", "fd00::2", 5353, 53).unwrap();
+        assert_eq!(derive(&v6), L3Type::Ipv6);
+        assert_eq!(derive(&v6.five_tuple), L3Type::Ipv6);
+    }
+
+    #[test]
+    fn test_comm_flag_implies_return_flow() {
+        let bi = CommFlag::BiDirectional;
+        let uni = CommFlag::UniDirectional;
+        let re = CommFlag::ReRequest(42);
+
+        assert!(bi.implies_return_flow());
+        assert!(!uni.implies_return_flow());
+        assert!(!re.implies_return_flow());
+
+        assert!(bi.implies_return_flow_with_prior(uni));
+        assert!(!uni.implies_return_flow_with_prior(bi));
+        assert!(re.implies_return_flow_with_prior(bi));
+        assert!(!re.implies_return_flow_with_prior(uni));
+        assert!(!re.implies_return_flow_with_prior(CommFlag::ReRequest(7)));
+    }
+
+    #[test]
+    fn test_write_display_reused_buffer() {
+        let v4 = tcp_tuple();
+        let v6 = VsapiFiveTuple::new(
+            L3Type::Ipv6,
+            IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, 1)),
+            IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, 2)),
+            vsapi_ip_number::UDP,
+            5353,
+            53,
+        );
+        assert_eq!(v4.to_string(), "10.0.0.1:40000 -> 10.0.0.2:443 proto 6");
+        assert_eq!(
+            v6.to_string(),
+            "[fd5a:5052::1]:5353 -> [fd5a:5052::2]:53 proto 17"
+        );
+
+        let mut buf = String::with_capacity(128);
+        for ft in [v4, v6, v4.reversed()] {
+            buf.clear();
+            ft.write_display(&mut buf).unwrap();
+            assert_eq!(buf, ft.to_string());
+        }
+    }
+
+    #[test]
+    fn test_five_tuple_canonical_key_ignores_direction() {
+        let ft = tcp_tuple();
+        let rev = ft.reversed();
+        assert_ne!(ft, rev);
+        assert_eq!(rev.src_addr(), ft.dst_addr());
+        assert_eq!(rev.src_port(), ft.dst_port());
+        assert_eq!(ft.canonical_key(), rev.canonical_key());
+    }
+
+    #[test]
+    fn test_five_tuple_socket_addrs_tcp() {
+        let (src, dst) = tcp_tuple().socket_addrs().unwrap();
+        assert_eq!(src, "10.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        assert_eq!(dst, "10.0.0.2:443".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_five_tuple_socket_addrs_icmp() {
+        let pd = PacketDesc::new_icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap();
+        assert!(pd.five_tuple.socket_addrs().is_none());
+    }
+
+    #[test]
+    fn test_five_tuple_from_ip_header_ipv4_tcp() {
+        let ft = VsapiFiveTuple::from_ip_header(
+            L3Type::Ipv4,
+            vsapi_ip_number::TCP,
+            &[10, 0, 0, 1],
+            &[10, 0, 0, 2],
+            40000,
+            443,
+        )
+        .unwrap();
+        assert_eq!(ft, tcp_tuple());
+    }
+
+    #[test]
+    fn test_five_tuple_from_ip_header_ipv6_udp() {
+        let src = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let dst = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let ft = VsapiFiveTuple::from_ip_header(
+            L3Type::Ipv6,
+            vsapi_ip_number::UDP,
+            &src.octets(),
+            &dst.octets(),
+            5353,
+            53,
+        )
+        .unwrap();
+        assert_eq!(ft.src_addr(), IpAddr::V6(src));
+        assert_eq!(ft.dst_addr(), IpAddr::V6(dst));
+        assert_eq!(ft.protocol(), vsapi_ip_number::UDP);
+        assert_eq!(ft.l3_type(), L3Type::Ipv6);
+        assert_eq!((ft.src_port(), ft.dst_port()), (5353, 53));
+    }
+
+    #[test]
+    fn test_five_tuple_from_ip_header_errors() {
+        // Truncated address.
+        assert!(
+            VsapiFiveTuple::from_ip_header(
+                L3Type::Ipv4,
+                vsapi_ip_number::TCP,
+                &[10, 0, 0],
+                &[10, 0, 0, 2],
+                1,
+                2
+            )
+            .is_err()
+        );
+        // Address family does not match the L3 type.
+        assert!(
+            VsapiFiveTuple::from_ip_header(
+                L3Type::Ipv6,
+                vsapi_ip_number::TCP,
+                &[10, 0, 0, 1],
+                &[10, 0, 0, 2],
+                1,
+                2
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_l3_type_icmp_protocol() {
+        assert_eq!(L3Type::Ipv4.icmp_protocol(), vsapi_ip_number::ICMP);
+        assert_eq!(L3Type::Ipv6.icmp_protocol(), vsapi_ip_number::IPV6_ICMP);
+        let pd = PacketDesc::new_icmp("fd00::1", "fd00::2", 128, 0).unwrap();
+        assert_eq!(pd.protocol(), vsapi_ip_number::IPV6_ICMP);
+    }
+}
@@ -1,9 +1,12 @@
+use std::collections::{BTreeMap, HashSet};
 use std::net::IpAddr;
 
 use crate::vsapi::v1;
 use crate::vsapi_types::AuthBlob;
+use crate::vsapi_types::AuthCodeBlob;
 use crate::vsapi_types::PacketDesc;
 use crate::vsapi_types::Param;
+use crate::vsapi_types::SelfSignedBlob;
 use crate::vsapi_types::VsapiTypeError;
 
 /// Request to connect to VS
@@ -35,10 +38,71 @@ pub struct Claim {
     pub value: String,
 }
 
+/// Claim keys with a well-known meaning: the registered JWT claims (RFC 7519)
+/// plus `cn`. Any other claim key is a custom claim.
+pub const STANDARD_CLAIM_KEYS: &[&str] = &["iss", "sub", "aud", "exp", "nbf", "iat", "jti", "cn"];
+
 impl Claim {
     pub fn new(key: String, value: String) -> Self {
         Self { key, value }
     }
+
+    /// True if the key is one of the [STANDARD_CLAIM_KEYS].
+    pub fn is_standard(&self) -> bool {
+        STANDARD_CLAIM_KEYS.contains(&self.key.as_str())
+    }
+}
+
+impl ConnectRequest {
+    /// Just the self-signed blobs, in order.
+    pub fn self_signed_blobs(&self) -> impl Iterator<Item = &SelfSignedBlob> {
+        self.blobs.iter().filter_map(|blob| match blob {
+            AuthBlob::SS(ss) => Some(ss),
+            _ => None,
+        })
+    }
+
+    /// Just the auth code blobs, in order.
+    pub fn auth_code_blobs(&self) -> impl Iterator<Item = &AuthCodeBlob> {
+        self.blobs.iter().filter_map(|blob| match blob {
+            AuthBlob::AC(ac) => Some(ac),
+            _ => None,
+        })
+    }
+
+    /// Check that no two claims share a key. Callers that collapse the claims
+    /// into a map should call this first so that duplicates are not silently lost.
+    pub fn validate(&self) -> Result<(), VsapiTypeError> {
+        let mut seen = HashSet::new();
+        for claim in &self.claims {
+            if !seen.insert(claim.key.as_str()) {
+                return Err(VsapiTypeError::ValidationError(format!(
+                    "duplicate claim key: {}",
+                    claim.key
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The claims whose keys are in [STANDARD_CLAIM_KEYS], keyed by claim key. If a
+    /// key is repeated the last value wins, see [ConnectRequest::validate].
+    pub fn standard_claims(&self) -> BTreeMap<&str, &str> {
+        self.claims_where(|claim| claim.is_standard())
+    }
+
+    /// The claims whose keys are not in [STANDARD_CLAIM_KEYS], keyed by claim key.
+    pub fn custom_claims(&self) -> BTreeMap<&str, &str> {
+        self.claims_where(|claim| !claim.is_standard())
+    }
+
+    fn claims_where(&self, pred: impl Fn(&Claim) -> bool) -> BTreeMap<&str, &str> {
+        self.claims
+            .iter()
+            .filter(|claim| pred(claim))
+            .map(|claim| (claim.key.as_str(), claim.value.as_str()))
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -132,6 +196,7 @@ impl TryFrom<v1::claim::Reader<'_>> for Claim {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::vsapi_types::ChallengeAlg;
     use crate::vsapi_types::ParamValue;
     use crate::write_to::WriteTo;
     use std::net::{IpAddr, Ipv4Addr};
@@ -249,4 +314,115 @@ mod tests {
         ));
         assert!(matches!(params[1].value, ParamValue::U64Param(u64::MAX)));
     }
+
+    fn make_connect_request(claims: Vec<Claim>) -> ConnectRequest {
+        ConnectRequest {
+            blobs: Vec::new(),
+            claims,
+            substrate_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dock_interface: 0,
+        }
+    }
+
+    #[test]
+    fn test_connect_request_validate_ok() {
+        let req = make_connect_request(vec![
+            Claim::new("cn".to_string(), "alice".to_string()),
+            Claim::new("role".to_string(), "admin".to_string()),
+        ]);
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_connect_request_validate_duplicate_claim() {
+        let req = make_connect_request(vec![
+            Claim::new("role".to_string(), "admin".to_string()),
+            Claim::new("cn".to_string(), "alice".to_string()),
+            Claim::new("role".to_string(), "ops".to_string()),
+        ]);
+        let err = req.validate().unwrap_err();
+        assert!(matches!(err, VsapiTypeError::ValidationError(_)));
+        assert!(err.to_string().contains("role"));
+    }
+
+    #[test]
+    fn test_connect_request_standard_and_custom_claims() {
+        let req = make_connect_request(vec![
+            Claim::new("sub".to_string(), "alice@example.com".to_string()),
+            Claim::new("role".to_string(), "admin".to_string()),
+            Claim::new("cn".to_string(), "alice".to_string()),
+            Claim::new("zpr.team".to_string(), "blue".to_string()),
+            Claim::new("exp".to_string(), "1700000000".to_string()),
+        ]);
+
+        let standard = req.standard_claims();
+        assert_eq!(standard.len(), 3);
+        assert_eq!(standard.get("sub"), Some(&"alice@example.com"));
+        assert_eq!(standard.get("cn"), Some(&"alice"));
+        assert_eq!(standard.get("exp"), Some(&"1700000000"));
+
+        let custom = req.custom_claims();
+        assert_eq!(custom.len(), 2);
+        assert_eq!(custom.get("role"), Some(&"admin"));
+        assert_eq!(custom.get("zpr.team"), Some(&"blue"));
+
+        assert!(
+            make_connect_request(Vec::new())
+                .standard_claims()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_connect_request_blob_iterators() {
+        let mut req = make_connect_request(Vec::new());
+        req.blobs.push(AuthBlob::AC(AuthCodeBlob {
+            asa_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)),
+            code: "code".to_string(),
+            pkce: "pkce".to_string(),
+            client_id: "client".to_string(),
+        }));
+        let mut ss = SelfSignedBlob::default();
+        ss.cn = "alice".to_string();
+        req.blobs.push(AuthBlob::SS(ss));
+
+        let ss: Vec<&SelfSignedBlob> = req.self_signed_blobs().collect();
+        assert_eq!(ss.len(), 1);
+        assert_eq!(ss[0].cn, "alice");
+
+        let ac: Vec<&AuthCodeBlob> = req.auth_code_blobs().collect();
+        assert_eq!(ac.len(), 1);
+        assert_eq!(ac[0].client_id, "client");
+    }
+
+    #[test]
+    fn test_connect_request_self_signed_blob_round_trip() {
+        let mut req = make_connect_request(vec![Claim::new("cn".to_string(), "alice".to_string())]);
+        req.blobs.push(AuthBlob::SS(
+            SelfSignedBlob::new(
+                ChallengeAlg::RsaSha256Pkcs1v15,
+                vec![1, 2, 3, 4],
+                "alice.example".to_string(),
+                1_700_000_000,
+                vec![9, 8, 7],
+            )
+            .unwrap(),
+        ));
+
+        let mut msg = capnp::message::Builder::new_default();
+        {
+            let mut root: v1::connect_request::Builder<'_> = msg.init_root();
+            req.write_to(&mut root);
+        }
+        let reader: v1::connect_request::Reader<'_> = msg.get_root_as_reader().unwrap();
+        let decoded = ConnectRequest::try_from(reader).unwrap();
+
+        let ss: Vec<&SelfSignedBlob> = decoded.self_signed_blobs().collect();
+        assert_eq!(ss.len(), 1);
+        assert_eq!(ss[0].alg(), ChallengeAlg::RsaSha256Pkcs1v15);
+        assert_eq!(ss[0].challenge, vec![1, 2, 3, 4]);
+        assert_eq!(ss[0].cn, "alice.example");
+        assert_eq!(ss[0].timestamp, 1_700_000_000);
+        assert_eq!(ss[0].signature, vec![9, 8, 7]);
+    }
 }
@@ -4,12 +4,15 @@ use std::time::SystemTime;
 
 use crate::vsapi::v1;
 use crate::vsapi_types::error::ErrorCode;
+use crate::vsapi_types::message::write_root;
+use crate::vsapi_types::util::time::try_visa_expiration_timestamp_to_system_time;
 use crate::vsapi_types::{ApiResponseError, Visa, VsapiTypeError};
 
 /// Info recieved from VS in response to ConnectRequest
 #[derive(Debug)]
 pub struct Connection {
     pub zpr_addr: IpAddr,
+    /// Seconds since the UNIX epoch. See [Connection::expires_at].
     pub auth_expires: u64,
 }
 
@@ -44,7 +47,7 @@ pub enum DisconnectReason {
 }
 
 /// Denial code, match the codes in vs.capnp, except for Fail
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum DenyCode {
     NoReason,
     NoMatch,
@@ -55,6 +58,8 @@ pub enum DenyCode {
     DestAuthError,
     QuotaExceeded,
     NoRoute,
+    /// Generic failure, used when there is only a free text reason.
+    Fail,
 }
 
 #[derive(Debug)]
@@ -74,6 +79,30 @@ impl Connection {
                 .as_secs(),
         }
     }
+
+    /// When the authentication expires. `auth_expires` is in seconds since the
+    /// UNIX epoch, the same unit as a visa expiration. None if it is too large
+    /// to represent, which in practice means it never expires.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        try_visa_expiration_timestamp_to_system_time(self.auth_expires).ok()
+    }
+
+    /// True if the authentication has expired, ie the connection must be renewed.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .is_some_and(|expires| SystemTime::now() >= expires)
+    }
+}
+
+impl VisaResponse {
+    /// Serialize into a framed Cap'n Proto `VisaResponse` message, ready to write
+    /// to the socket.
+    pub fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        if let VisaResponse::Allowed(visa) = self {
+            visa.check_serializable()?;
+        }
+        write_root::<v1::visa_response::Owned, _>(self)
+    }
 }
 
 impl Denied {
@@ -82,6 +111,59 @@ impl Denied {
     }
 }
 
+/// Reason phrases and the codes they map to. More specific phrases come first
+/// since they are matched as substrings.
+const DENY_REASON_PHRASES: &[(&str, DenyCode)] = &[
+    ("no reason", DenyCode::NoReason),
+    ("no match", DenyCode::NoMatch),
+    ("source not found", DenyCode::SourceNotFound),
+    ("dest not found", DenyCode::DestNotFound),
+    ("destination not found", DenyCode::DestNotFound),
+    ("source auth error", DenyCode::SourceAuthError),
+    ("dest auth error", DenyCode::DestAuthError),
+    ("destination auth error", DenyCode::DestAuthError),
+    ("quota exceeded", DenyCode::QuotaExceeded),
+    ("no route", DenyCode::NoRoute),
+    ("denied", DenyCode::Denied),
+];
+
+impl DenyCode {
+    /// Map a free text denial reason to the most specific code we recognize in it
+    /// (case insensitive), or [DenyCode::Fail] if none match.
+    pub fn from_reason_str(reason: &str) -> DenyCode {
+        let reason = reason.to_lowercase();
+        DENY_REASON_PHRASES
+            .iter()
+            .find(|(phrase, _)| reason.contains(phrase))
+            .map(|(_, code)| code.clone())
+            .unwrap_or(DenyCode::Fail)
+    }
+
+    /// HTTP-like status for surfacing a denial through an API gateway.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            DenyCode::NoReason | DenyCode::NoMatch | DenyCode::Denied => 403,
+            DenyCode::SourceNotFound | DenyCode::DestNotFound => 404,
+            DenyCode::SourceAuthError | DenyCode::DestAuthError => 401,
+            DenyCode::QuotaExceeded => 429,
+            DenyCode::NoRoute => 502,
+            DenyCode::Fail => 500,
+        }
+    }
+
+    /// The standard HTTP reason phrase for [DenyCode::http_status].
+    pub fn reason_phrase(&self) -> &'static str {
+        match self.http_status() {
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            429 => "Too Many Requests",
+            502 => "Bad Gateway",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
 impl fmt::Display for DenyCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -173,6 +255,7 @@ impl From<DenyCode> for v1::VisaDenyCode {
             DenyCode::DestAuthError => v1::VisaDenyCode::DestAuthError,
             DenyCode::QuotaExceeded => v1::VisaDenyCode::QuotaExceeded,
             DenyCode::NoRoute => v1::VisaDenyCode::NoRoute,
+            DenyCode::Fail => v1::VisaDenyCode::NoReason,
         }
     }
 }
@@ -217,3 +300,125 @@ impl From<v1::ErrorCode> for ErrorCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsapi_types::read_root;
+    use crate::vsapi_types::visa::make_visa;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn decode_response(bytes: &[u8]) -> VisaResponse {
+        let reader = read_root(bytes).unwrap();
+        let root: v1::visa_response::Reader<'_> = reader.get_root().unwrap();
+        VisaResponse::try_from(root).unwrap()
+    }
+
+    #[test]
+    fn test_deny_code_http_status() {
+        assert_eq!(DenyCode::Denied.http_status(), 403);
+        assert_eq!(DenyCode::Denied.reason_phrase(), "Forbidden");
+        assert_eq!(DenyCode::QuotaExceeded.http_status(), 429);
+        assert_eq!(DenyCode::QuotaExceeded.reason_phrase(), "Too Many Requests");
+        assert_eq!(DenyCode::SourceNotFound.http_status(), 404);
+        assert_eq!(DenyCode::DestNotFound.http_status(), 404);
+        assert_eq!(DenyCode::DestNotFound.reason_phrase(), "Not Found");
+        assert_eq!(DenyCode::SourceAuthError.http_status(), 401);
+        assert_eq!(DenyCode::NoRoute.http_status(), 502);
+        assert_eq!(DenyCode::Fail.http_status(), 500);
+        assert_eq!(DenyCode::Fail.reason_phrase(), "Internal Server Error");
+    }
+
+    #[test]
+    fn test_visa_response_to_capnp_bytes_allow() {
+        let bytes = VisaResponse::Allowed(make_visa()).to_capnp_bytes().unwrap();
+        match decode_response(&bytes) {
+            VisaResponse::Allowed(v) => assert_eq!(v.issuer_id, 42),
+            other => panic!("expected Allowed, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_visa_response_to_capnp_bytes_deny() {
+        let resp = VisaResponse::Denied(Denied::new(
+            DenyCode::QuotaExceeded,
+            Some("over quota".to_string()),
+        ));
+        match decode_response(&resp.to_capnp_bytes().unwrap()) {
+            VisaResponse::Denied(d) => {
+                assert_eq!(d.code, DenyCode::QuotaExceeded);
+                assert_eq!(d.reason, None);
+            }
+            other => panic!("expected Denied, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_visa_response_to_capnp_bytes_error() {
+        let resp = VisaResponse::VsapiTypeError(ApiResponseError {
+            code: ErrorCode::TemporarilyUnavailable,
+            message: "try later".to_string(),
+            retry_in: 30,
+        });
+        match decode_response(&resp.to_capnp_bytes().unwrap()) {
+            VisaResponse::VsapiTypeError(e) => {
+                assert!(matches!(e.code, ErrorCode::TemporarilyUnavailable));
+                assert_eq!(e.message, "try later");
+                assert_eq!(e.retry_in, 30);
+            }
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_connection_expires_at() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let when = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let conn = Connection::new(addr, when);
+        assert_eq!(conn.auth_expires, 1_700_000_000);
+        assert_eq!(conn.expires_at(), Some(when));
+    }
+
+    #[test]
+    fn test_connection_expires_out_of_range() {
+        let conn = Connection {
+            zpr_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            auth_expires: u64::MAX,
+        };
+        assert_eq!(conn.expires_at(), None);
+        assert!(!conn.is_expired());
+    }
+
+    #[test]
+    fn test_connection_is_expired() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let past = Connection::new(addr, SystemTime::now() - Duration::from_secs(60));
+        assert!(past.is_expired());
+        let future = Connection::new(addr, SystemTime::now() + Duration::from_secs(3600));
+        assert!(!future.is_expired());
+    }
+
+    #[test]
+    fn test_deny_code_from_reason_str() {
+        assert_eq!(DenyCode::from_reason_str("no match"), DenyCode::NoMatch);
+        assert_eq!(
+            DenyCode::from_reason_str("Quota Exceeded for data cap"),
+            DenyCode::QuotaExceeded
+        );
+        assert_eq!(
+            DenyCode::from_reason_str("denied: destination not found"),
+            DenyCode::DestNotFound
+        );
+        assert_eq!(
+            DenyCode::from_reason_str("denied by policy"),
+            DenyCode::Denied
+        );
+    }
+
+    #[test]
+    fn test_deny_code_from_unknown_reason_is_fail() {
+        assert_eq!(DenyCode::from_reason_str("something odd"), DenyCode::Fail);
+        assert_eq!(DenyCode::from_reason_str(""), DenyCode::Fail);
+    }
+}
@@ -2,6 +2,8 @@ use std::net::IpAddr;
 use std::time::SystemTime;
 use url::Url;
 
+#[cfg(feature = "policy")]
+use crate::policy_types::ServiceType;
 use crate::vsapi::v1;
 use crate::vsapi_types::VsapiTypeError;
 
@@ -21,16 +23,35 @@ pub struct ServiceDescriptor {
     pub zpr_addr: IpAddr,
 }
 
+/// The default is [AuthServicesList::invalid].
 impl Default for AuthServicesList {
     fn default() -> Self {
+        AuthServicesList::invalid()
+    }
+}
+
+impl AuthServicesList {
+    /// An empty list that expired at the epoch, ie one that is never
+    /// [valid](AuthServicesList::is_valid). Use as a placeholder until the
+    /// first real list arrives.
+    pub fn invalid() -> Self {
         AuthServicesList {
             expiration: Some(SystemTime::UNIX_EPOCH),
             services: Vec::new(),
         }
     }
-}
 
-impl AuthServicesList {
+    /// A list of `services` with no expiration.
+    pub fn never_expires(services: Vec<ServiceDescriptor>) -> Self {
+        AuthServicesList {
+            expiration: None,
+            services,
+        }
+    }
+
+    /// Replace the expiration and services. The services keep the caller's order,
+    /// which need not be stable across refreshes; call [AuthServicesList::sort]
+    /// afterwards if selection should be deterministic.
     pub fn update(&mut self, expiration: Option<SystemTime>, services: Vec<ServiceDescriptor>) {
         self.expiration = expiration;
         self.services = services;
@@ -48,6 +69,36 @@ impl AuthServicesList {
         self.services.is_empty()
     }
 
+    /// Merge in a newer list. Services are unioned by `service_id`, with `other`
+    /// winning on conflict; new services are appended. The later expiration is
+    /// kept, where `None` (no expiration) is later than any time.
+    pub fn merge(&mut self, other: AuthServicesList) {
+        self.expiration = match (self.expiration, other.expiration) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+        for svc in other.services {
+            match self
+                .services
+                .iter_mut()
+                .find(|s| s.service_id == svc.service_id)
+            {
+                Some(existing) => *existing = svc,
+                None => self.services.push(svc),
+            }
+        }
+    }
+
+    /// Order the services by service id, then ZPR address, so that picking "the
+    /// first" service gives the same answer for the same set of services.
+    pub fn sort(&mut self) {
+        self.services.sort_by(|a, b| {
+            a.service_id
+                .cmp(&b.service_id)
+                .then_with(|| a.zpr_addr.cmp(&b.zpr_addr))
+        });
+    }
+
     /// The list is "valid" it is non-empty and not expired.
     pub fn is_valid(&self) -> bool {
         !self.is_empty() && !self.is_expired()
@@ -55,11 +106,30 @@ impl AuthServicesList {
 }
 
 impl ServiceDescriptor {
+    /// Build a descriptor whose `zpr_addr` and URI port come from `sa`, with a
+    /// URI like `<scheme>://<ip>:<port>/auth`. Handy for tests and mocks.
+    ///
+    /// Note that [ServiceDescriptor::get_socket_addr] will not give `sa` back if the
+    /// port is the default for `scheme` (eg, 443 for https), since the URI drops it.
+    pub fn from_socket_addr(id: impl Into<String>, scheme: &str, sa: std::net::SocketAddr) -> Self {
+        ServiceDescriptor {
+            service_id: id.into(),
+            service_uri: format!("{scheme}://{sa}/auth"),
+            zpr_addr: sa.ip(),
+        }
+    }
+
+    /// Parse the service URI. Callers that need several parts of the URI (eg the
+    /// port and the path) should parse once with this and keep the result.
+    pub fn parsed_uri(&self) -> Result<Url, VsapiTypeError> {
+        Ok(Url::parse(&self.service_uri)?)
+    }
+
     /// Gently try to extract a SocketAddr from this ServiceDescriptor.
     /// If there are any problems, None is returned.
     pub fn get_socket_addr(&self) -> Option<std::net::SocketAddr> {
         // To create a socket addr we need a port, which is on the URI.
-        let uri = match Url::parse(&self.service_uri) {
+        let uri = match self.parsed_uri() {
             Ok(u) => u,
             Err(_) => return None, // Invalid URI
         };
@@ -69,6 +139,18 @@ impl ServiceDescriptor {
         };
         Some(std::net::SocketAddr::new(self.zpr_addr.into(), port))
     }
+
+    /// Resolve the host in the service URI to all candidate socket addresses.
+    /// Unlike [ServiceDescriptor::get_socket_addr] this ignores `zpr_addr`, which
+    /// is useful when ZPR routing is not yet available.
+    ///
+    /// Note that this performs a blocking DNS lookup when the host is a name.
+    pub fn get_socket_addr_by_host(&self) -> std::io::Result<Vec<std::net::SocketAddr>> {
+        let uri = self
+            .parsed_uri()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        uri.socket_addrs(|| None)
+    }
 }
 
 impl TryFrom<v1::service_descriptor::Reader<'_>> for ServiceDescriptor {
@@ -90,3 +172,27 @@ impl TryFrom<v1::service_descriptor::Reader<'_>> for ServiceDescriptor {
         })
     }
 }
+
+/// Only authentication services have a vsapi service type, the rest are an error.
+#[cfg(feature = "policy")]
+impl TryFrom<&ServiceType> for v1::ServiceT {
+    type Error = VsapiTypeError;
+
+    fn try_from(service_type: &ServiceType) -> Result<Self, Self::Error> {
+        match service_type {
+            ServiceType::Authentication => Ok(v1::ServiceT::ActorAuthentication),
+            other => Err(VsapiTypeError::ValidationError(format!(
+                "service type {other} has no vsapi service type"
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "policy")]
+impl From<v1::ServiceT> for ServiceType {
+    fn from(stype: v1::ServiceT) -> Self {
+        match stype {
+            v1::ServiceT::ActorAuthentication => ServiceType::Authentication,
+        }
+    }
+}
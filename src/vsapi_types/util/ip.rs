@@ -3,15 +3,67 @@ use std::net::IpAddr;
 
 /// Create an ip address from a Vector, or return an error if the vector is badly formatted
 pub fn ip_addr_from_vec(v: Vec<u8>) -> Result<IpAddr, VsapiTypeError> {
+    ip_addr_from_slice(&v)
+}
+
+/// Create an ip address from a 4 or 16 byte slice, or return an error if the slice is badly formatted
+pub fn ip_addr_from_slice(v: &[u8]) -> Result<IpAddr, VsapiTypeError> {
     match v.len() {
-        4 => Ok(IpAddr::from(
-            <[u8; 4]>::try_from(v.as_slice()).expect("Bad IP length"),
-        )),
-        16 => Ok(IpAddr::from(
-            <[u8; 16]>::try_from(v.as_slice()).expect("Bad IP length"),
-        )),
-        _ => Err(VsapiTypeError::DeserializationError(
-            "Bad IP Address format",
-        )),
+        4 => Ok(IpAddr::from(<[u8; 4]>::try_from(v)?)),
+        16 => Ok(IpAddr::from(<[u8; 16]>::try_from(v)?)),
+        len => Err(VsapiTypeError::DeserializationContext(format!(
+            "bad IP address length: {len}"
+        ))),
+    }
+}
+
+/// Unwrap an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to its IPv4 form. Any other
+/// address is returned unchanged.
+///
+/// [ip_addr_from_vec] picks the family by length alone, so a 16 byte mapped address
+/// comes back as V6. Apply this to addresses from the wire before deriving an
+/// [L3Type](crate::packet_info::L3Type) or building a five tuple that has to match
+/// traffic seen on an IPv4 socket.
+pub fn canonicalize_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_ip_addr_from_slice_bad_length() {
+        let err = ip_addr_from_slice(&[1, 2, 3, 4, 5, 6, 7]).unwrap_err();
+        assert!(matches!(err, VsapiTypeError::DeserializationContext(_)));
+        assert!(err.to_string().contains("length: 7"));
+    }
+
+    #[test]
+    fn test_canonicalize_ipv4_mapped() {
+        let mapped: IpAddr = "::ffff:192.168.0.1".parse().unwrap();
+        assert!(mapped.is_ipv6());
+        assert_eq!(
+            canonicalize_ip(mapped),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_leaves_other_addrs() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        assert_eq!(canonicalize_ip(v4), v4);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(canonicalize_ip(v6), v6);
+        // IPv4-compatible (deprecated) addresses are not unwrapped.
+        let compat: IpAddr = "::192.168.0.1".parse().unwrap();
+        assert_eq!(canonicalize_ip(compat), compat);
     }
 }
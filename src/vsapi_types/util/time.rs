@@ -1,6 +1,120 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// Convert a visa expiration timestamp (seconds since UNIX epoch) to SystemTime.
+use crate::vsapi_types::VsapiTypeError;
+
+/// Convert an expiration timestamp in seconds since UNIX epoch to SystemTime.
+/// Panics if the timestamp is too large to represent. Note that visas carry
+/// their expiration in milliseconds, see [ExpirationUnit].
 pub fn visa_expiration_timestamp_to_system_time(timestamp: u64) -> SystemTime {
     UNIX_EPOCH + Duration::from_secs(timestamp)
 }
+
+/// Like [visa_expiration_timestamp_to_system_time] but returns an error rather
+/// than panicking if the timestamp is out of range.
+pub(crate) fn try_visa_expiration_timestamp_to_system_time(
+    timestamp: u64,
+) -> Result<SystemTime, VsapiTypeError> {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(timestamp))
+        .ok_or_else(|| {
+            VsapiTypeError::DeserializationContext(format!(
+                "visa expiration out of range: {timestamp}"
+            ))
+        })
+}
+
+/// Unit of an expiration timestamp on the wire. The two are easy to mix up:
+/// [crate::vsapi_types::Visa::get_expiration_timestamp] writes milliseconds,
+/// while services list and connection expirations are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpirationUnit {
+    Seconds,
+    Milliseconds,
+}
+
+/// Expirations before 2000-01-01 or after 2200-01-01 (in seconds since the epoch)
+/// are almost certainly a seconds/milliseconds mix up.
+const MIN_PLAUSIBLE_EXPIRATION_SECS: u64 = 946_684_800;
+const MAX_PLAUSIBLE_EXPIRATION_SECS: u64 = 7_258_118_400;
+
+impl ExpirationUnit {
+    /// Duration since the UNIX epoch of a `timestamp` in this unit.
+    pub fn to_duration(self, timestamp: u64) -> Duration {
+        match self {
+            ExpirationUnit::Seconds => Duration::from_secs(timestamp),
+            ExpirationUnit::Milliseconds => Duration::from_millis(timestamp),
+        }
+    }
+
+    /// Timestamp in this unit for `time`. Times before the epoch map to 0.
+    pub fn from_system_time(self, time: SystemTime) -> u64 {
+        let dur = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self {
+            ExpirationUnit::Seconds => dur.as_secs(),
+            ExpirationUnit::Milliseconds => dur.as_millis() as u64,
+        }
+    }
+
+    /// Convert a `timestamp` in this unit to a SystemTime. A timestamp of 0 means
+    /// "unset" and maps to the epoch. Any other timestamp that falls outside the
+    /// years 2000 to 2200 is rejected since it was most likely written in the
+    /// other unit.
+    pub fn to_system_time(self, timestamp: u64) -> Result<SystemTime, VsapiTypeError> {
+        if timestamp == 0 {
+            return Ok(UNIX_EPOCH);
+        }
+        let secs = self.to_duration(timestamp).as_secs();
+        if !(MIN_PLAUSIBLE_EXPIRATION_SECS..=MAX_PLAUSIBLE_EXPIRATION_SECS).contains(&secs) {
+            return Err(VsapiTypeError::DeserializationContext(format!(
+                "implausible expiration {timestamp} as {self:?}, wrong unit?"
+            )));
+        }
+        Ok(UNIX_EPOCH + self.to_duration(timestamp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiration_unit_seconds() {
+        let t = ExpirationUnit::Seconds
+            .to_system_time(1_700_000_000)
+            .unwrap();
+        assert_eq!(t, UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(ExpirationUnit::Seconds.from_system_time(t), 1_700_000_000);
+        assert_eq!(
+            ExpirationUnit::Milliseconds.from_system_time(t),
+            1_700_000_000_000
+        );
+        assert_eq!(
+            ExpirationUnit::Milliseconds
+                .to_system_time(1_700_000_000_000)
+                .unwrap(),
+            t
+        );
+        assert_eq!(
+            ExpirationUnit::Seconds.to_system_time(0).unwrap(),
+            UNIX_EPOCH
+        );
+    }
+
+    #[test]
+    fn test_expiration_unit_mismatch_rejected() {
+        // Milliseconds read as seconds lands tens of thousands of years out.
+        let err = ExpirationUnit::Seconds
+            .to_system_time(1_700_000_000_000)
+            .unwrap_err();
+        assert!(matches!(err, VsapiTypeError::DeserializationContext(_)));
+        assert!(err.to_string().contains("wrong unit"));
+
+        // Seconds read as milliseconds lands in January 1970.
+        assert!(
+            ExpirationUnit::Milliseconds
+                .to_system_time(1_700_000_000)
+                .is_err()
+        );
+        assert!(ExpirationUnit::Seconds.to_system_time(u64::MAX).is_err());
+    }
+}
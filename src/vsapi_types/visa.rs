@@ -1,13 +1,17 @@
-use std::io::Cursor;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::IpAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::packet_info::L3Type;
 use crate::vsapi::v1;
 use crate::vsapi_types::VsapiFiveTuple;
 use crate::vsapi_types::VsapiTypeError;
+use crate::vsapi_types::message::{read_root_with_options, write_list_root, write_root};
 use crate::vsapi_types::packet::HasFiveTuple;
-use crate::vsapi_types::util::time::visa_expiration_timestamp_to_system_time;
+use crate::vsapi_types::packet::{CommFlag, PacketDesc};
+use crate::vsapi_types::util::ip::ip_addr_from_slice;
+use crate::vsapi_types::util::time::ExpirationUnit;
 use crate::vsapi_types::vsapi_ip_number;
 
 /// Structure representing the Visa
@@ -25,6 +29,29 @@ pub struct Visa {
     pub cons: Option<Constraints>,
 }
 
+/// Compact, serializable summary of a granted visa for audit logs. See
+/// [Visa::audit_record].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisaAuditRecord {
+    pub issuer_id: u64,
+    /// Dock pep source address, None for a forward-only visa.
+    pub source: Option<String>,
+    /// Dock pep dest address, None for a forward-only visa.
+    pub dest: Option<String>,
+    /// Protocol name (eg "TCP"), or the number if it has no name. None for a
+    /// forward-only visa.
+    pub protocol: Option<String>,
+    /// Expiration in milliseconds since the UNIX epoch, as on the wire.
+    pub expires_ms: u64,
+}
+
+/// The visas for each hop of a multi-hop grant, in path order. On the wire
+/// this is a `List(Visa)`.
+#[derive(Debug, Clone, Default)]
+pub struct VisaPath {
+    pub hops: Vec<Visa>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VisaType {
     /// An ingress or egress visa. If ingress, might have a [FwdPep]. Will have a [DockPep].
@@ -105,7 +132,9 @@ pub enum VisaOp {
     RevokeVisaId(u64),
 }
 
-#[derive(Debug, Clone)]
+/// An all default (zero/empty) `Constraints` means no constraints, the same as
+/// `None` on [Visa::cons]. See [Visa::effective_constraints].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Constraints {
     /// not set or none means no bandwidth constraint
     pub bw: bool,
@@ -127,8 +156,43 @@ impl KeySet {
     }
 }
 
+impl Constraints {
+    /// Constraints that constrain nothing.
+    pub const fn none() -> Self {
+        Constraints {
+            bw: false,
+            bw_limit_bps: 0,
+            data_cap_id: String::new(),
+            data_cap_bytes: 0,
+            data_cap_affinity_addr: Vec::new(),
+        }
+    }
+
+    /// True if these are the same as [Constraints::none].
+    pub fn is_none(&self) -> bool {
+        *self == Self::none()
+    }
+
+    /// The data cap affinity (tether) address as an IP address. None if unset.
+    pub fn affinity_addr(&self) -> Option<Result<IpAddr, VsapiTypeError>> {
+        if self.data_cap_affinity_addr.is_empty() {
+            None
+        } else {
+            Some(ip_addr_from_slice(&self.data_cap_affinity_addr))
+        }
+    }
+
+    pub fn set_affinity_addr(&mut self, addr: IpAddr) {
+        self.data_cap_affinity_addr = match addr {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        };
+    }
+}
+
 impl Visa {
-    /// Create a new "full" visa with no forwarding information.
+    /// Create a new "full" visa with no forwarding information. Empty constraints
+    /// are stored as `None`.
     pub fn new(
         issuer_id: u64,
         config: i64,
@@ -152,16 +216,58 @@ impl Visa {
             visa_type: VisaType::Full,
             dock_pep: Some(dock_pep),
             fwd_pep: None,
-            cons,
+            cons: cons.filter(|c| !c.is_none()),
         }
     }
 
+    /// The visa constraints, treating [Constraints::none] the same as no constraints.
+    pub fn effective_constraints(&self) -> Option<&Constraints> {
+        self.cons.as_ref().filter(|c| !c.is_none())
+    }
+
     pub fn from_capnp_bytes(bytes: &[u8]) -> Result<Self, VsapiTypeError> {
-        let mut cur = Cursor::new(bytes);
-        let reader =
-            capnp::serialize::read_message(&mut cur, capnp::message::ReaderOptions::new())?;
-        let visa_reader = reader.get_root::<v1::visa::Reader>()?;
-        Visa::try_from(visa_reader)
+        Visa::from_capnp_bytes_with_options(bytes, capnp::message::ReaderOptions::new())
+    }
+
+    /// Like [Visa::from_capnp_bytes] but with caller supplied reader options, eg
+    /// a lower traversal limit to reject oversized messages.
+    pub fn from_capnp_bytes_with_options(
+        bytes: &[u8],
+        opts: capnp::message::ReaderOptions,
+    ) -> Result<Self, VsapiTypeError> {
+        Visa::try_from(read_root_with_options(bytes, opts)?.get_root::<v1::visa::Reader>()?)
+    }
+
+    /// Serialize into a framed Cap'n Proto `Visa` message. Inverse of [Visa::from_capnp_bytes].
+    /// Errors for a visa with constraints, which cannot be serialized yet.
+    pub fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        self.check_serializable()?;
+        write_root::<v1::visa::Owned, _>(self)
+    }
+
+    /// A 64 bit fingerprint of the visa, computed (FNV-1a) over its Cap'n Proto
+    /// serialization so that two logically identical visas fingerprint the same.
+    /// Useful as a cache key. This is NOT a cryptographic hash. Errors for a visa
+    /// with constraints, see [Visa::to_capnp_bytes].
+    pub fn fingerprint(&self) -> Result<u64, VsapiTypeError> {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let bytes = self.to_capnp_bytes()?;
+        Ok(bytes.iter().fold(FNV_OFFSET_BASIS, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+        }))
+    }
+
+    /// Errors if the visa cannot be written by its `WriteTo` impl, which does
+    /// not support constraints yet.
+    pub(crate) fn check_serializable(&self) -> Result<(), VsapiTypeError> {
+        if self.effective_constraints().is_some() {
+            return Err(VsapiTypeError::SerializationError(
+                "visa constraints serialization not implemented yet",
+            ));
+        }
+        Ok(())
     }
 
     /// Get the expiration in milliseconds since UNIX epoch (which is how visa service formats it).
@@ -172,42 +278,251 @@ impl Visa {
         }
     }
 
-    /// Helper to get the five tuple if it exists.
+    /// The audit log record for this visa.
+    pub fn audit_record(&self) -> VisaAuditRecord {
+        let ft = self.five_tuple();
+        VisaAuditRecord {
+            issuer_id: self.issuer_id,
+            source: ft.map(|ft| ft.source_addr.to_string()),
+            dest: ft.map(|ft| ft.dest_addr.to_string()),
+            protocol: ft.map(|ft| match vsapi_ip_number::name(ft.l4_protocol) {
+                Some(name) => name.to_string(),
+                None => ft.l4_protocol.to_string(),
+            }),
+            expires_ms: self.get_expiration_timestamp(),
+        }
+    }
+
+    /// A one line human readable summary of the visa, eg for the admin CLI.
+    pub fn describe(&self) -> String {
+        let mut out = String::with_capacity(128);
+        // Writing to a String cannot fail.
+        let _ = self.write_description(&mut out);
+        out
+    }
+
+    /// Write the [Visa::describe] summary into `out`, so a caller can reuse a buffer.
+    pub fn write_description(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(out, "visa {}", self.issuer_id)?;
+        if let Some(dock_pep) = &self.dock_pep {
+            let proto = match &dock_pep.pep {
+                DockPepType::TCP(_) => vsapi_ip_number::TCP,
+                DockPepType::UDP(_) => vsapi_ip_number::UDP,
+                DockPepType::ICMP(_) => {
+                    L3Type::new_from_addr(&dock_pep.source_addr).icmp_protocol()
+                }
+            };
+            write!(
+                out,
+                " {} {} -> {}",
+                vsapi_ip_number::name(proto).unwrap_or("?"),
+                dock_pep.source_addr,
+                dock_pep.dest_addr
+            )?;
+            match &dock_pep.pep {
+                DockPepType::TCP(pep) | DockPepType::UDP(pep) => write!(
+                    out,
+                    " ports {} -> {} ({:?})",
+                    pep.source_port, pep.dest_port, pep.endpoint
+                )?,
+                DockPepType::ICMP(pep) => {
+                    write!(out, " type {} code {}", pep.icmp_type, pep.icmp_code)?
+                }
+            }
+        }
+        if let Some(fwd_pep) = &self.fwd_pep {
+            write!(out, " next hop {} ({:?})", fwd_pep.next_hop, fwd_pep.style)?;
+        }
+        let expires = self
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        write!(out, ", expires {expires} (unix secs)")?;
+        let keys = self.dock_pep.as_ref().map(|dp| &dp.session_key);
+        let ingress = keys.is_some_and(|k| !k.ingress_key.is_empty());
+        let egress = keys.is_some_and(|k| !k.egress_key.is_empty());
+        match (ingress, egress) {
+            (true, true) => write!(out, ", keys: ingress egress"),
+            (true, false) => write!(out, ", keys: ingress"),
+            (false, true) => write!(out, ", keys: egress"),
+            (false, false) => write!(out, ", no keys"),
+        }
+    }
+
+    /// The address family covered by this visa. Errors if the visa has no dock pep
+    /// (a forward-only visa) or if the dock pep mixes IPv4 and IPv6 addresses.
+    pub fn l3_type(&self) -> Result<L3Type, VsapiTypeError> {
+        match &self.dock_pep {
+            Some(dock_pep) => dock_pep.l3_type(),
+            None => Err(VsapiTypeError::ValidationError(
+                "visa has no dock pep".to_string(),
+            )),
+        }
+    }
+
+    /// The packet description this visa covers, eg to re-request it. A
+    /// [CommFlag::ReRequest] is given this visa's issuer id. Errors for a visa
+    /// with no dock pep.
+    pub fn to_packet_desc(&self, comm: CommFlag) -> Result<PacketDesc, VsapiTypeError> {
+        let five_tuple = match &self.dock_pep {
+            Some(dock_pep) => dock_pep.try_five_tuple()?,
+            None => {
+                return Err(VsapiTypeError::ValidationError(
+                    "visa has no dock pep".to_string(),
+                ));
+            }
+        };
+        let comm_flags = match comm {
+            CommFlag::ReRequest(_) => CommFlag::ReRequest(self.issuer_id),
+            other => other,
+        };
+        Ok(PacketDesc {
+            five_tuple,
+            comm_flags,
+        })
+    }
+
+    /// True if the visa expires more than `max` after `now`.
+    pub fn ttl_exceeds(&self, max: Duration, now: SystemTime) -> bool {
+        match now.checked_add(max) {
+            Some(limit) => self.expires > limit,
+            None => false,
+        }
+    }
+
+    /// Shorten the expiration to `now + max_ttl` if it is further out than that.
+    pub fn clamp_expiry(&mut self, max_ttl: Duration, now: SystemTime) {
+        // ttl_exceeds is only true if now + max_ttl does not overflow.
+        if self.ttl_exceeds(max_ttl, now) {
+            self.expires = now + max_ttl;
+        }
+    }
+
+    /// Helper to get the five tuple if it exists. None if there is no dock pep or
+    /// its address families differ, see [DockPep::try_five_tuple].
     pub fn five_tuple(&self) -> Option<VsapiFiveTuple> {
         self.dock_pep
             .as_ref()
-            .map(|dock_pep| dock_pep.get_five_tuple())
+            .and_then(|dock_pep| dock_pep.try_five_tuple().ok())
     }
-}
 
-impl HasFiveTuple for DockPep {
-    /// Get the FiveTuple from a Visa
-    fn get_five_tuple(&self) -> VsapiFiveTuple {
-        let source_addr = self.source_addr;
-        let dest_addr = self.dest_addr;
+    /// True if the visa is for a flow to the visa service, see
+    /// [VsapiFiveTuple::is_visa_service_flow].
+    pub fn is_visa_service_flow(&self) -> bool {
+        self.five_tuple()
+            .is_some_and(|ft| ft.is_visa_service_flow())
+    }
 
-        let l3_protocol = if source_addr.is_ipv4() {
-            L3Type::Ipv4
-        } else {
-            L3Type::Ipv6
+    /// True if the visa also covers the return direction, ie its forward pep
+    /// is [FwdPepStyle::Symmetric].
+    pub fn is_bidirectional(&self) -> bool {
+        self.fwd_pep
+            .as_ref()
+            .is_some_and(|fwd| fwd.style == FwdPepStyle::Symmetric)
+    }
+
+    /// True if `pkt` may use this visa. The packet must match the dock pep as
+    /// is (see [DockPep::matches_five_tuple]), or, if the visa is
+    /// [bidirectional](Visa::is_bidirectional), the packet reversed must match.
+    /// A visa with no dock pep covers nothing.
+    pub fn covers_packet(&self, pkt: &PacketDesc) -> bool {
+        let Some(dock_pep) = &self.dock_pep else {
+            return false;
         };
+        dock_pep.matches_five_tuple(&pkt.five_tuple)
+            || (self.is_bidirectional() && dock_pep.matches_five_tuple(&pkt.five_tuple.reversed()))
+    }
+}
 
-        let (l4_protocol, source_port, dest_port) = match &self.pep {
-            DockPepType::ICMP(icmp_pep) => {
-                if l3_protocol == L3Type::Ipv6 {
-                    (
-                        vsapi_ip_number::IPV6_ICMP,
-                        icmp_pep.icmp_type as u16,
-                        icmp_pep.icmp_code as u16,
-                    )
-                } else {
-                    (
-                        vsapi_ip_number::ICMP,
-                        icmp_pep.icmp_type as u16,
-                        icmp_pep.icmp_code as u16,
+impl DockPep {
+    /// True if `ft` has this pep's source and dest addresses, a protocol that
+    /// matches the pep type, and ports (or ICMP type and code) the pep allows.
+    pub fn matches_five_tuple(&self, ft: &VsapiFiveTuple) -> bool {
+        if ft.source_addr != self.source_addr || ft.dest_addr != self.dest_addr {
+            return false;
+        }
+        match &self.pep {
+            DockPepType::TCP(pep) => {
+                ft.l4_protocol == vsapi_ip_number::TCP && pep.matches(ft.source_port, ft.dest_port)
+            }
+            DockPepType::UDP(pep) => {
+                ft.l4_protocol == vsapi_ip_number::UDP && pep.matches(ft.source_port, ft.dest_port)
+            }
+            DockPepType::ICMP(pep) => {
+                vsapi_ip_number::is_icmp(ft.l4_protocol)
+                    && matches!(
+                        (u8::try_from(ft.source_port), u8::try_from(ft.dest_port)),
+                        (Ok(t), Ok(c)) if pep.matches(t, c)
                     )
-                }
             }
+        }
+    }
+
+    /// The shared address family of the source and dest addresses. Errors if
+    /// one is IPv4 and the other IPv6.
+    pub fn l3_type(&self) -> Result<L3Type, VsapiTypeError> {
+        let l3 = L3Type::new_from_addr(&self.source_addr);
+        if l3 != L3Type::new_from_addr(&self.dest_addr) {
+            return Err(VsapiTypeError::ValidationError(format!(
+                "dock pep address families differ: {} -> {}",
+                self.source_addr, self.dest_addr
+            )));
+        }
+        Ok(l3)
+    }
+
+    /// Build a dock pep covering the given five tuple. For TCP and UDP the ports are
+    /// used as-is, for ICMP the source and dest "ports" are the ICMP type and code.
+    ///
+    /// The session key is left empty (default) for the caller to fill in.
+    pub fn from_five_tuple(
+        tuple: &VsapiFiveTuple,
+        endpoint: EndpointT,
+    ) -> Result<DockPep, VsapiTypeError> {
+        let pep = match tuple.l4_protocol {
+            vsapi_ip_number::TCP => {
+                DockPepType::TCP(TcpUdpPep::new(tuple.source_port, tuple.dest_port, endpoint))
+            }
+            vsapi_ip_number::UDP => {
+                DockPepType::UDP(TcpUdpPep::new(tuple.source_port, tuple.dest_port, endpoint))
+            }
+            vsapi_ip_number::ICMP | vsapi_ip_number::IPV6_ICMP => {
+                let (icmp_type, icmp_code) = tuple.icmp_type_code()?;
+                DockPepType::ICMP(IcmpPep::new(icmp_type, icmp_code))
+            }
+            other => {
+                return Err(VsapiTypeError::ValidationError(format!(
+                    "no dock pep for protocol {other}"
+                )));
+            }
+        };
+        Ok(DockPep {
+            source_addr: tuple.source_addr,
+            dest_addr: tuple.dest_addr,
+            session_key: KeySet::default(),
+            pep,
+        })
+    }
+}
+
+impl DockPep {
+    /// The five tuple covered by this dock pep. Errors if the source and dest
+    /// address families differ, see [DockPep::l3_type].
+    pub fn try_five_tuple(&self) -> Result<VsapiFiveTuple, VsapiTypeError> {
+        self.l3_type().map(|l3| self.five_tuple_for(l3))
+    }
+
+    fn five_tuple_for(&self, l3_protocol: L3Type) -> VsapiFiveTuple {
+        let source_addr = self.source_addr;
+        let dest_addr = self.dest_addr;
+
+        let (l4_protocol, source_port, dest_port) = match &self.pep {
+            DockPepType::ICMP(icmp_pep) => (
+                l3_protocol.icmp_protocol(),
+                icmp_pep.icmp_type as u16,
+                icmp_pep.icmp_code as u16,
+            ),
             DockPepType::UDP(tcp_udp_pep) => (
                 vsapi_ip_number::UDP,
                 tcp_udp_pep.source_port,
@@ -231,6 +546,58 @@ impl HasFiveTuple for DockPep {
     }
 }
 
+impl HasFiveTuple for DockPep {
+    /// Get the FiveTuple from a Visa. The decoder rejects dock peps with mixed
+    /// address families; for one built by hand the family of the source address
+    /// is used. Use [DockPep::try_five_tuple] to catch that case.
+    fn get_five_tuple(&self) -> VsapiFiveTuple {
+        self.five_tuple_for(L3Type::new_from_addr(&self.source_addr))
+    }
+}
+
+impl VisaPath {
+    pub fn new(hops: Vec<Visa>) -> Self {
+        Self { hops }
+    }
+
+    pub fn from_capnp_bytes(bytes: &[u8]) -> Result<Self, VsapiTypeError> {
+        VisaPath::from_capnp_bytes_with_options(bytes, capnp::message::ReaderOptions::new())
+    }
+
+    /// Like [VisaPath::from_capnp_bytes] but with caller supplied reader options.
+    pub fn from_capnp_bytes_with_options(
+        bytes: &[u8],
+        opts: capnp::message::ReaderOptions,
+    ) -> Result<Self, VsapiTypeError> {
+        let reader = read_root_with_options(bytes, opts)?;
+        let list: capnp::struct_list::Reader<'_, v1::visa::Owned> = reader.get_root()?;
+        VisaPath::try_from(list)
+    }
+
+    /// Serialize into a framed Cap'n Proto message whose root is a `List(Visa)`.
+    /// Inverse of [VisaPath::from_capnp_bytes].
+    pub fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        for hop in &self.hops {
+            hop.check_serializable()?;
+        }
+        write_list_root::<v1::visa::Owned, _>(self, self.hops.len())
+    }
+}
+
+impl TryFrom<capnp::struct_list::Reader<'_, v1::visa::Owned>> for VisaPath {
+    type Error = VsapiTypeError;
+
+    fn try_from(
+        reader: capnp::struct_list::Reader<'_, v1::visa::Owned>,
+    ) -> Result<Self, Self::Error> {
+        let hops = reader
+            .iter()
+            .map(Visa::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(VisaPath { hops })
+    }
+}
+
 impl TcpUdpPep {
     pub fn new(source_port: u16, dest_port: u16, endpoint: EndpointT) -> Self {
         Self {
@@ -239,6 +606,18 @@ impl TcpUdpPep {
             endpoint,
         }
     }
+
+    /// True if a packet with these ports is covered by the pep. For a
+    /// [EndpointT::Server] pep only the dest port is fixed, for a
+    /// [EndpointT::Client] pep only the source port, and for [EndpointT::Any]
+    /// both must match.
+    pub fn matches(&self, src_port: u16, dst_port: u16) -> bool {
+        match self.endpoint {
+            EndpointT::Server => dst_port == self.dest_port,
+            EndpointT::Client => src_port == self.source_port,
+            EndpointT::Any => src_port == self.source_port && dst_port == self.dest_port,
+        }
+    }
 }
 
 impl IcmpPep {
@@ -248,6 +627,23 @@ impl IcmpPep {
             icmp_code,
         }
     }
+
+    /// Unpack the combined `icmp_type_code` word used on the wire, see
+    /// [vsapi_ip_number::pack_icmp_type_code].
+    pub fn from_type_code(word: u16) -> Self {
+        let (icmp_type, icmp_code) = vsapi_ip_number::unpack_icmp_type_code(word);
+        Self::new(icmp_type, icmp_code)
+    }
+
+    /// The combined `icmp_type_code` word for the wire, `(type << 8) | code`.
+    pub fn type_code(&self) -> u16 {
+        vsapi_ip_number::pack_icmp_type_code(self.icmp_type, self.icmp_code)
+    }
+
+    /// True if the ICMP type and code are the ones allowed by the pep.
+    pub fn matches(&self, icmp_type: u8, icmp_code: u8) -> bool {
+        icmp_type == self.icmp_type && icmp_code == self.icmp_code
+    }
 }
 
 impl TryFrom<v1::visa::Reader<'_>> for Visa {
@@ -257,7 +653,8 @@ impl TryFrom<v1::visa::Reader<'_>> for Visa {
     fn try_from(reader: v1::visa::Reader) -> Result<Self, Self::Error> {
         let issuer_id = reader.get_issuer_id();
         let config = 0i64;
-        let expires = visa_expiration_timestamp_to_system_time(reader.get_expiration());
+        // Written in milliseconds, see Visa::get_expiration_timestamp.
+        let expires = ExpirationUnit::Milliseconds.to_system_time(reader.get_expiration())?;
 
         let visa_type = match reader.get_visa_type()? {
             v1::VisaType::Full => VisaType::Full,
@@ -266,6 +663,10 @@ impl TryFrom<v1::visa::Reader<'_>> for Visa {
 
         let dock_pep = if reader.has_dock_pep() {
             Some(DockPep::try_from(reader.get_dock_pep()?)?)
+        } else if visa_type == VisaType::Full {
+            return Err(VsapiTypeError::DeserializationError(
+                "visa missing dock pep",
+            ));
         } else {
             None
         };
@@ -328,6 +729,13 @@ impl TryFrom<v1::dock_pep::Reader<'_>> for DockPep {
 
     /// Returns err if required values are not set
     fn try_from(reader: v1::dock_pep::Reader) -> Result<Self, Self::Error> {
+        // An unset union reads as a TCP pep with all default values, so check
+        // for it explicitly rather than decode a bogus pep.
+        if !(reader.has_tcp() || reader.has_udp() || reader.has_icmp()) {
+            return Err(VsapiTypeError::DeserializationError(
+                "visa missing dock pep",
+            ));
+        }
         let source_addr = IpAddr::try_from(reader.get_source_addr()?)?;
         let dest_addr = IpAddr::try_from(reader.get_dest_addr()?)?;
         let session_key = KeySet::try_from(reader.get_session_key()?)?;
@@ -359,17 +767,18 @@ impl TryFrom<v1::dock_pep::Reader<'_>> for DockPep {
             }
             v1::dock_pep::Which::Icmp(icmp_pep_result) => {
                 let icmp_pep_reader = icmp_pep_result?;
-                let type_code = icmp_pep_reader.get_icmp_type_code();
-                let icmp_pep = IcmpPep::new(type_code as u8, 0);
+                let icmp_pep = IcmpPep::from_type_code(icmp_pep_reader.get_icmp_type_code());
                 DockPepType::ICMP(icmp_pep)
             }
         };
-        Ok(DockPep {
+        let dock_pep = DockPep {
             source_addr,
             dest_addr,
             session_key,
             pep,
-        })
+        };
+        dock_pep.l3_type()?;
+        Ok(dock_pep)
     }
 }
 
@@ -391,3 +800,520 @@ impl TryFrom<v1::key_set::Reader<'_>> for KeySet {
         })
     }
 }
+
+/// A full TCP visa from 10.0.0.1 to 10.0.0.2 port 443, for tests in any module.
+#[cfg(test)]
+pub(crate) fn make_visa() -> Visa {
+    make_visa_with_constraints(None)
+}
+
+/// Like [make_visa], with `cons` passed to [Visa::new].
+#[cfg(test)]
+pub(crate) fn make_visa_with_constraints(cons: Option<Constraints>) -> Visa {
+    Visa::new(
+        42,
+        0,
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)),
+        IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)),
+        DockPepType::TCP(TcpUdpPep::new(0, 443, EndpointT::Server)),
+        KeySet::new(&[1, 2, 3], &[4, 5, 6]),
+        cons,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsapi_types::VisaResponse;
+    use crate::write_to::WriteTo;
+    use std::net::Ipv4Addr;
+
+    fn make_constraints(affinity: Vec<u8>) -> Constraints {
+        Constraints {
+            bw: false,
+            bw_limit_bps: 0,
+            data_cap_id: String::new(),
+            data_cap_bytes: 0,
+            data_cap_affinity_addr: affinity,
+        }
+    }
+
+    #[test]
+    fn test_affinity_addr_empty() {
+        let cons = make_constraints(vec![]);
+        assert!(cons.affinity_addr().is_none());
+    }
+
+    #[test]
+    fn test_affinity_addr_ipv4() {
+        let cons = make_constraints(vec![10, 1, 2, 3]);
+        let addr = cons.affinity_addr().unwrap().unwrap();
+        assert_eq!(addr, IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)));
+    }
+
+    #[test]
+    fn test_affinity_addr_malformed() {
+        let cons = make_constraints(vec![10, 1, 2]);
+        assert!(matches!(
+            cons.affinity_addr(),
+            Some(Err(VsapiTypeError::DeserializationContext(_)))
+        ));
+    }
+
+    #[test]
+    fn test_set_affinity_addr() {
+        let mut cons = make_constraints(vec![]);
+        let addr: IpAddr = "fd5a:5052::10".parse().unwrap();
+        cons.set_affinity_addr(addr);
+        assert_eq!(cons.data_cap_affinity_addr.len(), 16);
+        assert_eq!(cons.affinity_addr().unwrap().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_constraints_none_is_default() {
+        assert_eq!(Constraints::none(), Constraints::default());
+        assert!(Constraints::none().is_none());
+        assert!(!make_constraints(vec![10, 1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn test_effective_constraints() {
+        let mut visa = make_visa();
+        assert!(visa.effective_constraints().is_none());
+
+        // Built directly, bypassing Visa::new normalization.
+        visa.cons = Some(Constraints::none());
+        assert!(visa.effective_constraints().is_none());
+
+        let cons = make_constraints(vec![10, 1, 2, 3]);
+        visa.cons = Some(cons.clone());
+        assert_eq!(visa.effective_constraints(), Some(&cons));
+    }
+
+    #[test]
+    fn test_new_visa_normalizes_empty_constraints() {
+        let visa = make_visa_with_constraints(Some(Constraints::default()));
+        assert!(visa.cons.is_none());
+        assert_eq!(
+            visa.fingerprint().unwrap(),
+            make_visa().fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_clone_is_equal() {
+        let visa = make_visa();
+        assert_eq!(
+            visa.fingerprint().unwrap(),
+            visa.clone().fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_modified_differs() {
+        let visa = make_visa();
+        let mut other = visa.clone();
+        other.issuer_id += 1;
+        assert_ne!(visa.fingerprint().unwrap(), other.fingerprint().unwrap());
+    }
+
+    #[test]
+    fn test_constraints_not_serializable() {
+        let mut visa = make_visa();
+        visa.cons = Some(Constraints {
+            bw: true,
+            bw_limit_bps: 1_000_000,
+            ..make_constraints(vec![])
+        });
+        assert!(matches!(
+            visa.fingerprint(),
+            Err(VsapiTypeError::SerializationError(_))
+        ));
+        assert!(matches!(
+            visa.to_capnp_bytes(),
+            Err(VsapiTypeError::SerializationError(_))
+        ));
+        assert!(
+            VisaPath::new(vec![make_visa(), visa.clone()])
+                .to_capnp_bytes()
+                .is_err()
+        );
+        assert!(VisaResponse::Allowed(visa).to_capnp_bytes().is_err());
+    }
+
+    #[test]
+    fn test_tcp_udp_pep_matches() {
+        let server = TcpUdpPep::new(0, 443, EndpointT::Server);
+        assert!(server.matches(40000, 443));
+        assert!(server.matches(40001, 443));
+        assert!(!server.matches(443, 40000));
+
+        let client = TcpUdpPep::new(5353, 0, EndpointT::Client);
+        assert!(client.matches(5353, 53));
+        assert!(client.matches(5353, 5300));
+        assert!(!client.matches(5354, 53));
+
+        let any = TcpUdpPep::new(40000, 443, EndpointT::Any);
+        assert!(any.matches(40000, 443));
+        assert!(!any.matches(40001, 443));
+        assert!(!any.matches(40000, 444));
+    }
+
+    #[test]
+    fn test_icmp_visa_round_trip() {
+        let mut visa = make_visa();
+        visa.dock_pep.as_mut().unwrap().pep = DockPepType::ICMP(IcmpPep::new(3, 13));
+        let decoded = Visa::from_capnp_bytes(&visa.to_capnp_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.expires, visa.expires);
+        assert!(matches!(
+            decoded.dock_pep.unwrap().pep,
+            DockPepType::ICMP(IcmpPep {
+                icmp_type: 3,
+                icmp_code: 13
+            })
+        ));
+    }
+
+    #[test]
+    fn test_visa_audit_record() {
+        let visa = make_visa();
+        let record = visa.audit_record();
+        assert_eq!(record.issuer_id, 42);
+        assert_eq!(record.source.as_deref(), Some("10.0.0.1"));
+        assert_eq!(record.dest.as_deref(), Some("10.0.0.2"));
+        assert_eq!(record.protocol.as_deref(), Some("TCP"));
+        assert_eq!(record.expires_ms, 1_700_000_000_000);
+        assert_eq!(record.expires_ms, visa.get_expiration_timestamp());
+
+        let mut fwd_only = make_visa();
+        fwd_only.dock_pep = None;
+        let record = fwd_only.audit_record();
+        assert_eq!(record.source, None);
+        assert_eq!(record.protocol, None);
+    }
+
+    #[test]
+    fn test_visa_is_visa_service_flow() {
+        assert!(!make_visa().is_visa_service_flow());
+
+        let mut vs = make_visa();
+        let dock_pep = vs.dock_pep.as_mut().unwrap();
+        dock_pep.source_addr = "fd5a:5052::99".parse().unwrap();
+        dock_pep.dest_addr = crate::addrs::VISA_SERVICE_ADDR;
+        dock_pep.pep = DockPepType::TCP(TcpUdpPep::new(
+            0,
+            crate::addrs::VISA_SERVICE_PORT,
+            EndpointT::Server,
+        ));
+        assert!(vs.is_visa_service_flow());
+    }
+
+    #[test]
+    fn test_visa_covers_packet() {
+        let forward = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+        let reverse = PacketDesc::new_tcp("10.0.0.2", "10.0.0.1", 443, 40000).unwrap();
+        let other_port = PacketDesc::new_tcp("10.0.0.1", "10.0.0.2", 40000, 444).unwrap();
+        let udp = PacketDesc::new_udp("10.0.0.1", "10.0.0.2", 40000, 443).unwrap();
+
+        let mut visa = make_visa();
+        assert!(visa.covers_packet(&forward));
+        assert!(!visa.covers_packet(&other_port));
+        assert!(!visa.covers_packet(&udp));
+        assert!(!visa.covers_packet(&reverse));
+
+        visa.fwd_pep = Some(FwdPep {
+            next_hop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)),
+            style: FwdPepStyle::OneWay,
+        });
+        assert!(!visa.is_bidirectional());
+        assert!(!visa.covers_packet(&reverse));
+
+        visa.fwd_pep.as_mut().unwrap().style = FwdPepStyle::Symmetric;
+        assert!(visa.is_bidirectional());
+        assert!(visa.covers_packet(&forward));
+        assert!(visa.covers_packet(&reverse));
+        assert!(!visa.covers_packet(&other_port));
+    }
+
+    #[test]
+    fn test_icmp_pep_type_code() {
+        let pep = IcmpPep::new(3, 4);
+        assert_eq!(pep.type_code(), 0x0304);
+        let decoded = IcmpPep::from_type_code(0x0304);
+        assert_eq!(decoded.icmp_type, 3);
+        assert_eq!(decoded.icmp_code, 4);
+    }
+
+    #[test]
+    fn test_icmp_pep_matches() {
+        let echo = IcmpPep::new(8, 0);
+        assert!(echo.matches(8, 0));
+        assert!(!echo.matches(0, 0));
+        assert!(!echo.matches(8, 1));
+    }
+
+    fn make_five_tuple(proto: u8, src_port: u16, dst_port: u16) -> VsapiFiveTuple {
+        VsapiFiveTuple::new(
+            L3Type::Ipv4,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            proto,
+            src_port,
+            dst_port,
+        )
+    }
+
+    #[test]
+    fn test_dock_pep_from_five_tuple_tcp() {
+        let ft = make_five_tuple(vsapi_ip_number::TCP, 40000, 443);
+        let dp = DockPep::from_five_tuple(&ft, EndpointT::Server).unwrap();
+        assert!(matches!(
+            dp.pep,
+            DockPepType::TCP(TcpUdpPep {
+                source_port: 40000,
+                dest_port: 443,
+                endpoint: EndpointT::Server
+            })
+        ));
+        assert_eq!(dp.get_five_tuple(), ft);
+    }
+
+    #[test]
+    fn test_dock_pep_from_five_tuple_udp() {
+        let ft = make_five_tuple(vsapi_ip_number::UDP, 5353, 53);
+        let dp = DockPep::from_five_tuple(&ft, EndpointT::Client).unwrap();
+        assert!(matches!(
+            dp.pep,
+            DockPepType::UDP(TcpUdpPep {
+                source_port: 5353,
+                dest_port: 53,
+                endpoint: EndpointT::Client
+            })
+        ));
+        assert_eq!(dp.get_five_tuple(), ft);
+    }
+
+    #[test]
+    fn test_dock_pep_from_five_tuple_icmp() {
+        let ft = make_five_tuple(vsapi_ip_number::ICMP, 8, 0);
+        let dp = DockPep::from_five_tuple(&ft, EndpointT::Any).unwrap();
+        assert!(matches!(
+            dp.pep,
+            DockPepType::ICMP(IcmpPep {
+                icmp_type: 8,
+                icmp_code: 0
+            })
+        ));
+        assert_eq!(dp.get_five_tuple(), ft);
+
+        let ft = make_five_tuple(vsapi_ip_number::ICMP, 300, 0);
+        assert!(DockPep::from_five_tuple(&ft, EndpointT::Any).is_err());
+    }
+
+    #[test]
+    fn test_dock_pep_from_five_tuple_unsupported() {
+        let ft = make_five_tuple(vsapi_ip_number::AH, 0, 0);
+        assert!(matches!(
+            DockPep::from_five_tuple(&ft, EndpointT::Any),
+            Err(VsapiTypeError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_visa_l3_type() {
+        let visa = make_visa();
+        assert_eq!(visa.l3_type().unwrap(), L3Type::Ipv4);
+    }
+
+    #[test]
+    fn test_visa_l3_type_mixed_family() {
+        let mut visa = make_visa();
+        visa.dock_pep.as_mut().unwrap().dest_addr = "fd00::2".parse().unwrap();
+        assert!(matches!(
+            visa.l3_type(),
+            Err(VsapiTypeError::ValidationError(_))
+        ));
+
+        assert!(visa.dock_pep.as_ref().unwrap().try_five_tuple().is_err());
+        assert_eq!(visa.five_tuple(), None);
+        assert!(matches!(
+            visa.to_packet_desc(CommFlag::UniDirectional),
+            Err(VsapiTypeError::ValidationError(_))
+        ));
+
+        // Mixed family visas are rejected when decoded.
+        let bytes = visa.to_capnp_bytes().unwrap();
+        assert!(Visa::from_capnp_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_from_capnp_bytes_with_options_limit() {
+        let bytes = make_visa().to_capnp_bytes().unwrap();
+        let mut opts = capnp::message::ReaderOptions::new();
+        assert!(Visa::from_capnp_bytes_with_options(&bytes, opts).is_ok());
+
+        opts.traversal_limit_in_words(Some(1));
+        assert!(Visa::from_capnp_bytes_with_options(&bytes, opts).is_err());
+
+        let path = VisaPath::new(vec![make_visa(), make_visa()])
+            .to_capnp_bytes()
+            .unwrap();
+        assert!(VisaPath::from_capnp_bytes_with_options(&path, opts).is_err());
+    }
+
+    #[test]
+    fn test_visa_ttl_within_max() {
+        let mut visa = make_visa();
+        let now = visa.expires - Duration::from_secs(60);
+        let max = Duration::from_secs(300);
+        assert!(!visa.ttl_exceeds(max, now));
+        visa.clamp_expiry(max, now);
+        assert_eq!(visa.expires, now + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_visa_ttl_beyond_max() {
+        let mut visa = make_visa();
+        let now = visa.expires - Duration::from_secs(3600);
+        let max = Duration::from_secs(300);
+        assert!(visa.ttl_exceeds(max, now));
+        visa.clamp_expiry(max, now);
+        assert_eq!(visa.expires, now + max);
+        assert!(!visa.ttl_exceeds(max, now));
+    }
+
+    #[test]
+    fn test_visa_to_packet_desc() {
+        let visa = make_visa();
+        let pd = visa.to_packet_desc(CommFlag::ReRequest(0)).unwrap();
+        assert_eq!(*pd.source_addr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(*pd.dest_addr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(pd.protocol(), vsapi_ip_number::TCP);
+        assert_eq!(pd.source_port(), 0);
+        assert_eq!(pd.dest_port(), 443);
+        assert_eq!(pd.comm_flags, CommFlag::ReRequest(42));
+
+        let pd = visa.to_packet_desc(CommFlag::BiDirectional).unwrap();
+        assert_eq!(pd.comm_flags, CommFlag::BiDirectional);
+    }
+
+    #[test]
+    fn test_visa_missing_dock_pep() {
+        let missing = |err: VsapiTypeError| {
+            matches!(
+                err,
+                VsapiTypeError::DeserializationError("visa missing dock pep")
+            )
+        };
+
+        // A full visa with no dock pep at all.
+        let mut msg = capnp::message::Builder::new_default();
+        let mut root: v1::visa::Builder<'_> = msg.init_root();
+        root.set_visa_type(v1::VisaType::Full);
+        assert!(missing(Visa::try_from(root.into_reader()).unwrap_err()));
+
+        // A dock pep whose pep union was never set.
+        let mut msg = capnp::message::Builder::new_default();
+        let mut root: v1::visa::Builder<'_> = msg.init_root();
+        root.set_visa_type(v1::VisaType::Full);
+        {
+            let mut dp = root.reborrow().init_dock_pep();
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)).write_to(&mut dp.reborrow().init_source_addr());
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)).write_to(&mut dp.reborrow().init_dest_addr());
+            KeySet::new(&[1], &[2]).write_to(&mut dp.reborrow().init_session_key());
+        }
+        assert!(missing(Visa::try_from(root.into_reader()).unwrap_err()));
+    }
+
+    #[test]
+    fn test_visa_expiration_in_seconds_rejected() {
+        let mut msg = capnp::message::Builder::new_default();
+        {
+            let mut root: v1::visa::Builder<'_> = msg.init_root();
+            make_visa().write_to(&mut root);
+            // Seconds rather than the milliseconds the writer uses.
+            root.set_expiration(1_700_000_000);
+        }
+        let reader: v1::visa::Reader<'_> = msg.get_root_as_reader().unwrap();
+        assert!(matches!(
+            Visa::try_from(reader),
+            Err(VsapiTypeError::DeserializationContext(_))
+        ));
+    }
+
+    #[test]
+    fn test_forward_only_visa_to_packet_desc() {
+        let mut visa = make_visa();
+        visa.visa_type = VisaType::ForwardOnly;
+        visa.dock_pep = None;
+        assert!(visa.to_packet_desc(CommFlag::BiDirectional).is_err());
+    }
+
+    #[test]
+    fn test_visa_describe() {
+        let desc = make_visa().describe();
+        assert!(desc.contains("TCP"), "{desc}");
+        assert!(desc.contains("10.0.0.1"), "{desc}");
+        assert!(desc.contains("10.0.0.2"), "{desc}");
+        assert_eq!(
+            desc,
+            "visa 42 TCP 10.0.0.1 -> 10.0.0.2 ports 0 -> 443 (Server), \
+             expires 1700000000 (unix secs), keys: ingress egress"
+        );
+
+        let mut visa = make_visa();
+        visa.dock_pep = Some(
+            DockPep::from_five_tuple(
+                &make_five_tuple(vsapi_ip_number::ICMP, 8, 0),
+                EndpointT::Any,
+            )
+            .unwrap(),
+        );
+        let mut buf = String::new();
+        visa.write_description(&mut buf).unwrap();
+        assert!(
+            buf.contains("ICMP 10.0.0.1 -> 10.0.0.2 type 8 code 0"),
+            "{buf}"
+        );
+        assert!(buf.ends_with("no keys"), "{buf}");
+    }
+
+    #[test]
+    fn test_visa_path_two_hops() {
+        let mut fwd = make_visa();
+        fwd.issuer_id = 41;
+        fwd.visa_type = VisaType::ForwardOnly;
+        fwd.dock_pep = None;
+        fwd.fwd_pep = Some(FwdPep {
+            next_hop: "fd5a:5052::2".parse().unwrap(),
+            style: FwdPepStyle::Symmetric,
+        });
+        let path = VisaPath::new(vec![fwd, make_visa()]);
+
+        let bytes = path.to_capnp_bytes().unwrap();
+        let decoded = VisaPath::from_capnp_bytes(&bytes).unwrap();
+        assert_eq!(decoded.hops.len(), 2);
+
+        let hop0 = &decoded.hops[0];
+        assert_eq!(hop0.issuer_id, 41);
+        assert_eq!(hop0.visa_type, VisaType::ForwardOnly);
+        assert!(hop0.dock_pep.is_none());
+        let fpep = hop0.fwd_pep.as_ref().unwrap();
+        assert_eq!(fpep.next_hop, "fd5a:5052::2".parse::<IpAddr>().unwrap());
+        assert_eq!(fpep.style, FwdPepStyle::Symmetric);
+
+        let hop1 = &decoded.hops[1];
+        assert_eq!(hop1.issuer_id, 42);
+        assert_eq!(hop1.expires, make_visa().expires);
+        assert_eq!(hop1.visa_type, VisaType::Full);
+        assert!(hop1.dock_pep.is_some());
+
+        assert!(
+            VisaPath::from_capnp_bytes(&VisaPath::default().to_capnp_bytes().unwrap())
+                .unwrap()
+                .hops
+                .is_empty()
+        );
+    }
+}
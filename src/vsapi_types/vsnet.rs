@@ -1,5 +1,7 @@
+use crate::packet_info::L3Type;
 use crate::vsapi::v1;
 use crate::vsapi_types::VsapiTypeError;
+use crate::vsapi_types::message::{read_root_with_options, write_list_root, write_root};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -14,21 +16,63 @@ impl From<SockAddr> for SocketAddr {
     }
 }
 
+impl SockAddr {
+    pub fn from_capnp_bytes(bytes: &[u8]) -> Result<Self, VsapiTypeError> {
+        SockAddr::from_capnp_bytes_with_options(bytes, capnp::message::ReaderOptions::new())
+    }
+
+    /// Like [SockAddr::from_capnp_bytes] but with caller supplied reader options.
+    pub fn from_capnp_bytes_with_options(
+        bytes: &[u8],
+        opts: capnp::message::ReaderOptions,
+    ) -> Result<Self, VsapiTypeError> {
+        SockAddr::try_from(
+            read_root_with_options(bytes, opts)?.get_root::<v1::sock_addr::Reader>()?,
+        )
+    }
+
+    /// Serialize into a framed Cap'n Proto `SockAddr` message. Inverse of
+    /// [SockAddr::from_capnp_bytes].
+    pub fn to_capnp_bytes(&self) -> Result<Vec<u8>, VsapiTypeError> {
+        write_root::<v1::sock_addr::Owned, _>(self)
+    }
+
+    /// Decode a framed message whose root is a `List(SockAddr)`, eg a list of
+    /// candidate docks.
+    pub fn list_from_capnp_bytes(bytes: &[u8]) -> Result<Vec<Self>, VsapiTypeError> {
+        SockAddr::list_from_capnp_bytes_with_options(bytes, capnp::message::ReaderOptions::new())
+    }
+
+    /// Like [SockAddr::list_from_capnp_bytes] but with caller supplied reader options.
+    pub fn list_from_capnp_bytes_with_options(
+        bytes: &[u8],
+        opts: capnp::message::ReaderOptions,
+    ) -> Result<Vec<Self>, VsapiTypeError> {
+        let reader = read_root_with_options(bytes, opts)?;
+        let list: capnp::struct_list::Reader<'_, v1::sock_addr::Owned> = reader.get_root()?;
+        list.iter().map(SockAddr::try_from).collect()
+    }
+
+    /// Serialize `addrs` as a framed message whose root is a `List(SockAddr)`.
+    /// Inverse of [SockAddr::list_from_capnp_bytes].
+    pub fn list_to_capnp_bytes(addrs: &[SockAddr]) -> Result<Vec<u8>, VsapiTypeError> {
+        write_list_root::<v1::sock_addr::Owned, _>(addrs, addrs.len())
+    }
+}
+
+/// Order dock candidates for dialing: addresses of the `prefer` family first. The
+/// sort is stable, so the order given by the visa service is kept within each
+/// family.
+pub fn order_candidates(mut addrs: Vec<SockAddr>, prefer: L3Type) -> Vec<SockAddr> {
+    addrs.sort_by_key(|sa| L3Type::new_from_addr(&sa.addr) != prefer);
+    addrs
+}
+
 impl TryFrom<v1::sock_addr::Reader<'_>> for SockAddr {
     type Error = VsapiTypeError;
 
     fn try_from(reader: v1::sock_addr::Reader<'_>) -> Result<Self, Self::Error> {
-        let addr_rdr = reader.get_addr()?;
-        let addr = match addr_rdr.which()? {
-            v1::ip_addr::V4(ipv4) => {
-                let octets: [u8; 4] = ipv4?.try_into()?;
-                IpAddr::V4(Ipv4Addr::from(octets))
-            }
-            v1::ip_addr::V6(ipv6) => {
-                let octets: [u8; 16] = ipv6?.try_into()?;
-                IpAddr::V6(Ipv6Addr::from(octets))
-            }
-        };
+        let addr = IpAddr::try_from(reader.get_addr()?)?;
         let port = reader.get_port();
 
         Ok(SockAddr { addr, port })
@@ -41,13 +85,103 @@ impl TryFrom<v1::ip_addr::Reader<'_>> for IpAddr {
     fn try_from(reader: v1::ip_addr::Reader<'_>) -> Result<Self, Self::Error> {
         match reader.which()? {
             v1::ip_addr::V4(ipv4) => {
-                let octets: [u8; 4] = ipv4?.try_into()?;
+                let ipv4 = ipv4?;
+                let octets: [u8; 4] = ipv4.try_into().map_err(|_| {
+                    VsapiTypeError::DeserializationContext(format!(
+                        "bad IPv4 address length: {}",
+                        ipv4.len()
+                    ))
+                })?;
                 Ok(IpAddr::V4(Ipv4Addr::from(octets)))
             }
             v1::ip_addr::V6(ipv6) => {
-                let octets: [u8; 16] = ipv6?.try_into()?;
+                let ipv6 = ipv6?;
+                let octets: [u8; 16] = ipv6.try_into().map_err(|_| {
+                    VsapiTypeError::DeserializationContext(format!(
+                        "bad IPv6 address length: {}",
+                        ipv6.len()
+                    ))
+                })?;
                 Ok(IpAddr::V6(Ipv6Addr::from(octets)))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_candidates_prefer_ipv6() {
+        let sa = |s: &str| {
+            let sa: SocketAddr = s.parse().unwrap();
+            SockAddr {
+                addr: sa.ip(),
+                port: sa.port(),
+            }
+        };
+        let addrs = vec![
+            sa("192.0.2.1:5000"),
+            sa("[2001:db8::1]:5000"),
+            sa("192.0.2.2:5000"),
+            sa("[2001:db8::2]:5000"),
+        ];
+        let ordered = order_candidates(addrs.clone(), L3Type::Ipv6);
+        assert_eq!(
+            ordered,
+            vec![
+                addrs[1].clone(),
+                addrs[3].clone(),
+                addrs[0].clone(),
+                addrs[2].clone()
+            ]
+        );
+        let ordered = order_candidates(addrs.clone(), L3Type::Ipv4);
+        assert_eq!(ordered[0], addrs[0]);
+        assert_eq!(ordered[1], addrs[2]);
+    }
+
+    #[test]
+    fn test_sock_addr_capnp_round_trip() {
+        let sa = SockAddr {
+            addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            port: 5000,
+        };
+        let bytes = sa.to_capnp_bytes().unwrap();
+        assert_eq!(SockAddr::from_capnp_bytes(&bytes).unwrap(), sa);
+    }
+
+    #[test]
+    fn test_sock_addr_list_capnp_round_trip() {
+        let addrs = vec![
+            SockAddr {
+                addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                port: 5000,
+            },
+            SockAddr {
+                addr: IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, 1)),
+                port: 5001,
+            },
+        ];
+        let bytes = SockAddr::list_to_capnp_bytes(&addrs).unwrap();
+        assert_eq!(SockAddr::list_from_capnp_bytes(&bytes).unwrap(), addrs);
+
+        let empty = SockAddr::list_to_capnp_bytes(&[]).unwrap();
+        assert!(SockAddr::list_from_capnp_bytes(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sock_addr_with_options_limit() {
+        let sa = SockAddr {
+            addr: IpAddr::V6(Ipv6Addr::new(0xfd5a, 0x5052, 0, 0, 0, 0, 0, 1)),
+            port: 5000,
+        };
+        let mut opts = capnp::message::ReaderOptions::new();
+        opts.traversal_limit_in_words(Some(1));
+        let bytes = sa.to_capnp_bytes().unwrap();
+        assert!(SockAddr::from_capnp_bytes_with_options(&bytes, opts).is_err());
+        let list = SockAddr::list_to_capnp_bytes(&[sa.clone(), sa]).unwrap();
+        assert!(SockAddr::list_from_capnp_bytes_with_options(&list, opts).is_err());
+    }
+}
@@ -2,10 +2,10 @@ use std::net::IpAddr;
 
 use crate::vsapi::v1;
 use crate::vsapi_types::{
-    ApiResponseError, AuthBlob, ChallengeAlg, Claim, CommFlag, ConnectRequest, Connection, DockPep,
-    DockPepType, EndpointT, FwdPep, FwdPepStyle, IcmpPep, KeySet, Link, LinkRole, PacketDesc,
-    Param, ParamValue, ServiceDescriptor, SockAddr, TcpUdpPep, VSConnectRequest, Visa, VisaOp,
-    VisaType,
+    ApiResponseError, AuthBlob, Claim, CommFlag, ConnectRequest, Connection, DockPep, DockPepType,
+    EndpointT, FwdPep, FwdPepStyle, IcmpPep, KeySet, Link, LinkRole, PacketDesc, Param, ParamValue,
+    ServiceDescriptor, SockAddr, TcpUdpPep, VSConnectRequest, Visa, VisaOp, VisaPath, VisaResponse,
+    VisaType, vsapi_ip_number,
 };
 use crate::write_to::WriteTo;
 
@@ -17,6 +17,14 @@ impl WriteTo<v1::sock_addr::Builder<'_>> for SockAddr {
     }
 }
 
+impl WriteTo<capnp::struct_list::Builder<'_, v1::sock_addr::Owned>> for [SockAddr] {
+    fn write_to(&self, bldr: &mut capnp::struct_list::Builder<'_, v1::sock_addr::Owned>) {
+        for (i, addr) in self.iter().enumerate() {
+            addr.write_to(&mut bldr.reborrow().get(i as u32));
+        }
+    }
+}
+
 impl WriteTo<v1::link::Builder<'_>> for Link {
     fn write_to(&self, bldr: &mut v1::link::Builder<'_>) {
         bldr.set_link_id(&self.link_id);
@@ -44,7 +52,8 @@ impl WriteTo<v1::ip_addr::Builder<'_>> for IpAddr {
     }
 }
 
-// Write out a visa to a Cap'n Proto builder.
+// Write out a visa to a Cap'n Proto builder. Panics if the visa has constraints,
+// use Visa::to_capnp_bytes to get an error instead.
 impl WriteTo<v1::visa::Builder<'_>> for Visa {
     fn write_to(&self, bldr: &mut v1::visa::Builder<'_>) {
         bldr.set_issuer_id(self.issuer_id);
@@ -65,12 +74,21 @@ impl WriteTo<v1::visa::Builder<'_>> for Visa {
             fpep.write_to(&mut fwd_pep_bldr);
         }
 
-        if self.cons.is_some() {
+        // Visa::to_capnp_bytes and friends check for this before writing.
+        if self.effective_constraints().is_some() {
             unimplemented!("visa constraints serialization not implemented yet");
         }
     }
 }
 
+impl WriteTo<capnp::struct_list::Builder<'_, v1::visa::Owned>> for VisaPath {
+    fn write_to(&self, bldr: &mut capnp::struct_list::Builder<'_, v1::visa::Owned>) {
+        for (i, visa) in self.hops.iter().enumerate() {
+            visa.write_to(&mut bldr.reborrow().get(i as u32));
+        }
+    }
+}
+
 impl WriteTo<v1::fwd_pep::Builder<'_>> for FwdPep {
     fn write_to(&self, bldr: &mut v1::fwd_pep::Builder<'_>) {
         let mut ip_bldr = bldr.reborrow().init_next_hop();
@@ -123,8 +141,7 @@ impl WriteTo<v1::dock_pep_tcp_udp::Builder<'_>> for TcpUdpPep {
 
 impl WriteTo<v1::dock_pep_icmp::Builder<'_>> for IcmpPep {
     fn write_to(&self, bldr: &mut v1::dock_pep_icmp::Builder<'_>) {
-        let typecode: u16 = ((self.icmp_type as u16) << 8) | (self.icmp_code as u16);
-        bldr.set_icmp_type_code(typecode);
+        bldr.set_icmp_type_code(self.type_code());
     }
 }
 
@@ -143,8 +160,18 @@ impl WriteTo<v1::packet_desc::Builder<'_>> for PacketDesc {
         let mut ip_bldr = bldr.reborrow().init_dest_addr();
         self.dest_addr().write_to(&mut ip_bldr);
         bldr.set_protocol(self.protocol());
-        bldr.set_source_port(self.source_port());
-        bldr.set_dest_port(self.dest_port());
+        if vsapi_ip_number::is_icmp(self.protocol()) {
+            // Pack type and code the same way as an ICMP dock pep.
+            // PacketDesc::to_capnp_bytes rejects values that do not fit in a byte.
+            bldr.set_source_port(vsapi_ip_number::pack_icmp_type_code(
+                self.source_port() as u8,
+                self.dest_port() as u8,
+            ));
+            bldr.set_dest_port(0);
+        } else {
+            bldr.set_source_port(self.source_port());
+            bldr.set_dest_port(self.dest_port());
+        }
         match self.comm_flags {
             CommFlag::BiDirectional => bldr.set_comm_type(v1::CommType::Bidirectional),
             CommFlag::UniDirectional => bldr.set_comm_type(v1::CommType::Unidirectional),
@@ -177,6 +204,25 @@ impl WriteTo<v1::service_descriptor::Builder<'_>> for ServiceDescriptor {
     }
 }
 
+impl WriteTo<v1::visa_response::Builder<'_>> for VisaResponse {
+    /// The deny reason string is not part of the wire message and is dropped.
+    fn write_to(&self, bldr: &mut v1::visa_response::Builder<'_>) {
+        match self {
+            VisaResponse::Allowed(visa) => {
+                let mut visa_bldr = bldr.reborrow().init_allow();
+                visa.write_to(&mut visa_bldr);
+            }
+            VisaResponse::Denied(denied) => {
+                bldr.set_deny(denied.code.clone().into());
+            }
+            VisaResponse::VsapiTypeError(err) => {
+                let mut err_bldr = bldr.reborrow().init_error();
+                err.write_to(&mut err_bldr);
+            }
+        }
+    }
+}
+
 impl WriteTo<v1::error::Builder<'_>> for ApiResponseError {
     fn write_to(&self, bldr: &mut v1::error::Builder<'_>) {
         bldr.set_code(self.code.clone().into());
@@ -205,10 +251,7 @@ impl WriteTo<v1::auth_blob::Builder<'_>> for AuthBlob {
         match self {
             AuthBlob::SS(ss) => {
                 let mut ss_bldr = bldr.reborrow().init_ss();
-                let alg = match ss.alg {
-                    ChallengeAlg::RsaSha256Pkcs1v15 => v1::ChallengeAlg::RsaSha256Pkcs1v15,
-                };
-                ss_bldr.set_alg(alg);
+                ss_bldr.set_alg(ss.capnp_alg());
                 ss_bldr.set_challenge(&ss.challenge);
                 ss_bldr.set_cn(&ss.cn);
                 ss_bldr.set_timestamp(ss.timestamp);